@@ -1,16 +1,36 @@
-use nix;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::io;
 use std::io::prelude::*;
 use std::net::Ipv4Addr;
-use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::io::{AsRawFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
 use std::sync::Condvar;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time;
 
+#[cfg(test)]
+mod mock;
 mod tcp;
 
-const SENDQUEUE_SIZE: usize = 1024;
+pub use tcp::{CongestionControl, Cubic, KeepaliveConfig, Reno};
+
+const SENDQUEUE_SIZE: usize = 64 * 1024;
+
+/// How often the packet loop runs connection timers, even while packets keep arriving.
+const TICK_INTERVAL: time::Duration = time::Duration::from_millis(10);
+
+/// How many established connections a listener holds for `accept` unless told otherwise.
+const DEFAULT_BACKLOG: usize = 128;
+
+/// Source address used for connections we open ourselves.
+const LOCAL_ADDR: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+/// Local ports handed out to active opens (RFC 6335 dynamic range).
+const EPHEMERAL_PORTS: std::ops::RangeInclusive<u16> = 49152..=65535;
+
+/// How long `Interface::connect` waits for the handshake to complete.
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(30);
 
 type InterfaceHandle = Arc<Foobar>;
 
@@ -20,11 +40,21 @@ type InterfaceHandle = Arc<Foobar>;
 //     }
 // }
 
-#[derive(Default)]
 struct Foobar {
     manager: Mutex<ConnectionManager>,
     pending_var: Condvar,
     rcv_var: Condvar,
+    state_var: Condvar,
+    /// write end of a socket pair the packet loop polls alongside the device
+    wake: UnixStream,
+}
+
+impl Foobar {
+    /// Wake up the packet loop so it services connections without waiting for the poll timeout.
+    fn kick(&self) {
+        // a full socket buffer means a wakeup is already pending
+        let _ = (&self.wake).write(&[0]);
+    }
 }
 
 pub struct Interface {
@@ -38,124 +68,389 @@ struct Quad {
     dst: (Ipv4Addr, u16),
 }
 
-struct Pending {
-    quads: VecDeque<Quad>,
-    var: Condvar,
+/// The connections to a listening port that have not been accepted yet.
+struct Listener {
+    /// passive opens still in the middle of the handshake
+    syn_queue: HashSet<Quad>,
+    /// connections that completed the handshake, in the order they did
+    accept_queue: VecDeque<Quad>,
+    /// how many connections `accept_queue` holds at most
+    backlog: usize,
+}
+
+impl Listener {
+    /// Whether there is no room for another connection to complete its handshake.
+    fn is_full(&self) -> bool {
+        self.accept_queue.len() >= self.backlog
+    }
 }
 
 #[derive(Default)]
 struct ConnectionManager {
     terminate: bool,
     connections: HashMap<Quad, tcp::Connection>,
-    pending: HashMap<u16, VecDeque<Quad>>,
+    listeners: HashMap<u16, Listener>,
+    next_port: u16,
+    config: tcp::Config,
+    syn_cookies: tcp::SynCookies,
+}
+
+impl ConnectionManager {
+    /// Take a passive open whose handshake just ended off its listener's SYN queue, and put it on
+    /// the accept queue if it got established.
+    ///
+    /// Returns whether there is a new connection to accept.
+    fn handshake_done(&mut self, q: Quad) -> bool {
+        let Some(listener) = self.listeners.get_mut(&q.dst.1) else {
+            return false;
+        };
+        if !listener.syn_queue.remove(&q) {
+            return false;
+        }
+        let established = self
+            .connections
+            .get(&q)
+            .is_some_and(|c| c.state.is_synchronized());
+        if established {
+            listener.accept_queue.push_back(q);
+        }
+        established
+    }
+
+    /// Pick a local port that is neither bound by a listener nor used by an existing connection.
+    fn ephemeral_port(&mut self) -> Option<u16> {
+        let start = *EPHEMERAL_PORTS.start();
+        let nports = EPHEMERAL_PORTS.len() as u16;
+        for _ in 0..nports {
+            let port = start + self.next_port % nports;
+            self.next_port = self.next_port.wrapping_add(1);
+            if !self.listeners.contains_key(&port)
+                && !self.connections.keys().any(|q| q.dst.1 == port)
+            {
+                return Some(port);
+            }
+        }
+        None
+    }
 }
 
-fn packet_loop(mut dev: tun::platform::Device, ih: InterfaceHandle) -> io::Result<()> {
-    let mut buf = [0u8; 1504];
+fn packet_loop(
+    dev: tun::platform::Device,
+    wake: UnixStream,
+    ih: InterfaceHandle,
+) -> io::Result<()> {
+    let result = run_packet_loop(dev, wake, &ih);
+    loop_stopped(&ih);
+    result
+}
 
-    // TODO: fix
-    eprintln!("creatin dev file");
-    let dev_file = unsafe { std::fs::File::from_raw_fd(dev.as_raw_fd()) };
+/// Fail whatever is still waiting on the interface once the packet loop is gone.
+fn loop_stopped(ih: &InterfaceHandle) {
+    // whatever stopped the loop, nothing is going to make progress anymore
+    let mut cm = ih.manager.lock().unwrap();
+    cm.terminate = true;
+    for c in cm.connections.values_mut() {
+        if !matches!(c.state, tcp::State::Closed | tcp::State::TimeWait) {
+            c.state = tcp::State::Closed;
+            c.error.get_or_insert(io::ErrorKind::ConnectionAborted);
+        }
+    }
+    drop(cm);
+    ih.pending_var.notify_all();
+    ih.rcv_var.notify_all();
+    ih.state_var.notify_all();
+}
+
+fn run_packet_loop(
+    mut dev: tun::platform::Device,
+    wake: UnixStream,
+    ih: &InterfaceHandle,
+) -> io::Result<()> {
+    // room for a full packet after the 4 byte packet information header
+    let mtu = ih.manager.lock().unwrap().config.mtu;
+    let mut buf = vec![0u8; mtu + 4];
+    let mut shutdown_started = None;
+    let mut last_tick = time::Instant::now();
+
+    // the device itself keeps owning the descriptor
+    let dev_fd = unsafe { BorrowedFd::borrow_raw(dev.as_raw_fd()) };
 
     loop {
-        let mut pfd = [nix::poll::PollFd::new(
-            &dev_file,
-            nix::poll::PollFlags::POLLIN,
-        )];
-        let n = nix::poll::poll(&mut pfd[..], 10)?;
+        let mut pfd = [
+            nix::poll::PollFd::new(&dev_fd, nix::poll::PollFlags::POLLIN),
+            nix::poll::PollFd::new(&wake, nix::poll::PollFlags::POLLIN),
+        ];
+        let n = nix::poll::poll(&mut pfd[..], TICK_INTERVAL.as_millis() as i32)?;
         assert_ne!(n, -1);
-        if n == 0 {
-            // TODO: timed out
-            let mut cmg = ih.manager.lock().unwrap();
-            for connection in cmg.connections.values_mut() {
-                connection.on_tick(&mut dev)?;
+        let readable = |pfd: nix::poll::PollFd| {
+            pfd.revents()
+                .is_some_and(|r| r.contains(nix::poll::PollFlags::POLLIN))
+        };
+        let woken = readable(pfd[1]);
+        let dev_readable = readable(pfd[0]);
+
+        if woken {
+            let mut drain = [0u8; 64];
+            while let Ok(n) = (&wake).read(&mut drain) {
+                if n == 0 {
+                    break;
+                }
             }
-            continue;
         }
-        let nbytes = dev.read(&mut buf)?;
 
-        let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
-        let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
+        // a busy device must not starve retransmissions and other timers
+        if n == 0 || woken || last_tick.elapsed() >= TICK_INTERVAL {
+            last_tick = time::Instant::now();
+            if on_tick(&mut dev, ih, &mut shutdown_started)? {
+                return Ok(());
+            }
+        }
 
-        if eth_proto != 0x0002 {
-            // Ignore non-IPv4 packets
-            // note that macos uses different values for AF_INET and AF_INET6
-            // https://github.com/meh/rust-tun/issues/58
-            // https://opensource.apple.com/source/xnu/xnu-201/bsd/sys/socket.h
+        if !dev_readable {
             continue;
         }
+        let nbytes = dev.read(&mut buf)?;
+        on_datagram(&mut dev, ih, &buf[..nbytes])?;
+    }
+}
 
-        match etherparse::Ipv4HeaderSlice::from_slice(&buf[4..nbytes]) {
-            Ok(ip_header) => {
-                let src = ip_header.source_addr();
-                let dst = ip_header.destination_addr();
-                if ip_header.protocol() != 0x06 {
-                    // Ignore non-TCP packets
-                    continue;
+/// Run the timers of every connection, and wind them down once the `Interface` is dropped.
+///
+/// Returns whether the packet loop is done.
+fn on_tick(
+    dev: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ih: &InterfaceHandle,
+    shutdown_started: &mut Option<time::Instant>,
+) -> io::Result<bool> {
+    let mut cmg = ih.manager.lock().unwrap();
+    if cmg.terminate {
+        let cm = &mut *cmg;
+        let started = *shutdown_started.get_or_insert_with(|| {
+            let timeout = cm.config.shutdown_timeout;
+            for c in cm.connections.values_mut() {
+                if timeout.is_zero() || !c.state.is_synchronized() {
+                    // a handshake still in progress has no data to flush, and a FIN
+                    // before it completes would go out without a SYN to precede it
+                    c.abort();
+                } else {
+                    // an error only means there is nothing left to close
+                    let _ = c.close();
                 }
+            }
+            time::Instant::now()
+        });
+
+        let done = cm
+            .connections
+            .values()
+            .all(|c| matches!(c.state, tcp::State::Closed | tcp::State::TimeWait));
+        if done || started.elapsed() >= cm.config.shutdown_timeout {
+            for c in cm.connections.values_mut() {
+                c.abort();
+                c.on_tick(dev)?;
+            }
+            return Ok(true);
+        }
+    }
+
+    let mut changed = false;
+    let mut handshakes = Vec::new();
+    for (q, connection) in cmg.connections.iter_mut() {
+        let state = connection.state;
+        connection.on_tick(dev)?;
+        if connection.state != state {
+            changed = true;
+            if state == tcp::State::SynRcvd {
+                handshakes.push(*q);
+            }
+        }
+    }
+    let mut accepted = false;
+    for q in handshakes {
+        accepted |= cmg.handshake_done(q);
+    }
+    cmg.connections.retain(|_, c| !c.is_reapable());
+    drop(cmg);
+    if changed {
+        ih.state_var.notify_all();
+        // a connection that failed on a timer has an error for blocked readers
+        ih.rcv_var.notify_all();
+    }
+    if accepted {
+        ih.pending_var.notify_all();
+    }
+    Ok(false)
+}
+
+/// Hand a datagram read from the device, starting with its packet information header, to the
+/// connection or listener it is for.
+fn on_datagram(
+    dev: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ih: &InterfaceHandle,
+    buf: &[u8],
+) -> io::Result<()> {
+    let _eth_flags = u16::from_be_bytes([buf[0], buf[1]]);
+    let eth_proto = u16::from_be_bytes([buf[2], buf[3]]);
+
+    if eth_proto != 0x0002 {
+        // Ignore non-IPv4 packets
+        // note that macos uses different values for AF_INET and AF_INET6
+        // https://github.com/meh/rust-tun/issues/58
+        // https://opensource.apple.com/source/xnu/xnu-201/bsd/sys/socket.h
+        return Ok(());
+    }
+
+    match etherparse::Ipv4HeaderSlice::from_slice(&buf[4..]) {
+        Ok(ip_header) => {
+            let src = ip_header.source_addr();
+            let dst = ip_header.destination_addr();
+            if ip_header.protocol() != 0x06 {
+                // Ignore non-TCP packets
+                return Ok(());
+            }
+
+            match etherparse::TcpHeaderSlice::from_slice(&buf[4 + ip_header.slice().len()..]) {
+                Ok(tcp_header) => {
+                    let data_start = 4 + ip_header.slice().len() + tcp_header.slice().len();
+                    let mut cmg = ih.manager.lock().unwrap();
+                    let cm = &mut *cmg;
+                    let q = Quad {
+                        src: (src, tcp_header.source_port()),
+                        dst: (dst, tcp_header.destination_port()),
+                    };
+
+                    match cm.connections.get_mut(&q) {
+                        Some(connection) => {
+                            let state = connection.state;
+                            let waiting = cm
+                                .listeners
+                                .get(&q.dst.1)
+                                .is_some_and(|l| l.syn_queue.contains(&q) && l.is_full());
+                            if waiting && tcp_header.ack() && !tcp_header.rst() {
+                                // no room to accept the connection, so leave the handshake
+                                // hanging; the peer repeats its ACK when it gets our SYN,ACK
+                                // again
+                                return Ok(());
+                            }
+
+                            let a = connection.on_packet(
+                                dev,
+                                ip_header,
+                                tcp_header,
+                                &buf[data_start..],
+                            )?;
+                            let changed = connection.state != state;
+                            let accepted =
+                                changed && state == tcp::State::SynRcvd && cm.handshake_done(q);
 
-                match etherparse::TcpHeaderSlice::from_slice(
-                    &buf[4 + ip_header.slice().len()..nbytes],
-                ) {
-                    Ok(tcp_header) => {
-                        use std::collections::hash_map::Entry;
-                        let data_start = 4 + ip_header.slice().len() + tcp_header.slice().len();
-                        let mut cmg = ih.manager.lock().unwrap();
-                        let cm = &mut *cmg;
-                        let q = Quad {
-                            src: (src, tcp_header.source_port()),
-                            dst: (dst, tcp_header.destination_port()),
-                        };
-
-                        match cm.connections.entry(Quad {
-                            src: (src, tcp_header.source_port()),
-                            dst: (dst, tcp_header.destination_port()),
-                        }) {
-                            Entry::Occupied(mut connection) => {
-                                let a = connection.get_mut().on_packet(
-                                    &mut dev,
+                            drop(cmg);
+                            if changed {
+                                ih.state_var.notify_all();
+                            }
+                            if accepted {
+                                ih.pending_var.notify_all();
+                            }
+                            if a.contains(tcp::Available::READ) {
+                                ih.rcv_var.notify_all();
+                            }
+                            if a.contains(tcp::Available::WRITE) {
+                                // TODO
+                            }
+                        }
+                        None => {
+                            let listening =
+                                cm.listeners.contains_key(&tcp_header.destination_port());
+                            if listening
+                                && tcp_header.syn()
+                                && !tcp_header.ack()
+                                && !tcp_header.rst()
+                            {
+                                let listener = &cm.listeners[&q.dst.1];
+                                if listener.is_full() {
+                                    // nobody is accepting connections as fast as they come
+                                    // in, so let the peer retry its SYN later
+                                } else if listener.syn_queue.len() >= cm.config.syn_backlog {
+                                    // don't let a SYN flood eat up memory, the peer gets a
+                                    // SYN cookie instead
+                                    tcp::Connection::send_syn_cookie(
+                                        dev,
+                                        &cm.config,
+                                        &cm.syn_cookies,
+                                        &ip_header,
+                                        &tcp_header,
+                                    )?;
+                                } else if let Some(c) = tcp::Connection::accept(
+                                    dev,
+                                    &cm.config,
                                     ip_header,
                                     tcp_header,
-                                    &buf[data_start..nbytes],
-                                )?;
-
-                                drop(cmg);
-                                if a.contains(tcp::Available::READ) {
-                                    ih.rcv_var.notify_all();
+                                    &buf[data_start..],
+                                )? {
+                                    cm.connections.insert(q, c);
+                                    cm.listeners
+                                        .get_mut(&q.dst.1)
+                                        .expect("listening port has a listener")
+                                        .syn_queue
+                                        .insert(q);
                                 }
-                                if a.contains(tcp::Available::WRITE) {
-                                    // TODO
-                                }
-                            }
-                            Entry::Vacant(e) => {
-                                if let Some(pending) =
-                                    cm.pending.get_mut(&tcp_header.destination_port())
-                                {
-                                    if let Some(c) = tcp::Connection::accept(
-                                        &mut dev,
-                                        ip_header,
-                                        tcp_header,
-                                        &buf[data_start..nbytes],
-                                    )? {
-                                        e.insert(c);
-                                        pending.push_back(q);
+                            } else if (!listening || tcp_header.ack()) && !tcp_header.rst() {
+                                let cookie = if listening && !tcp_header.syn() {
+                                    tcp::Connection::from_cookie(
+                                        &cm.config,
+                                        &cm.syn_cookies,
+                                        &ip_header,
+                                        &tcp_header,
+                                    )
+                                } else {
+                                    None
+                                };
+                                if let Some(mut c) = cookie {
+                                    let listener = cm
+                                        .listeners
+                                        .get_mut(&q.dst.1)
+                                        .expect("listening port has a listener");
+                                    // like any other handshake, this one waits for room in
+                                    // the accept queue; the peer's next segment repeats the
+                                    // ACK
+                                    if !listener.is_full() {
+                                        // the handshake for a SYN we answered with a cookie
+                                        // is done
+                                        c.on_packet(
+                                            dev,
+                                            ip_header,
+                                            tcp_header,
+                                            &buf[data_start..],
+                                        )?;
+                                        listener.accept_queue.push_back(q);
+                                        cm.connections.insert(q, c);
                                         drop(cmg);
                                         ih.pending_var.notify_all();
                                     }
+                                } else {
+                                    // nothing is listening, or the segment belongs to a
+                                    // connection we have no record of (e.g. from before a
+                                    // restart); either way the sender should give up now
+                                    tcp::send_rst(
+                                        dev,
+                                        &ip_header,
+                                        &tcp_header,
+                                        buf.len() - data_start,
+                                    )?;
                                 }
                             }
                         }
                     }
-                    Err(e) => {
-                        eprintln!("Ignoring malformed TCP packet: {:?}", e)
-                    }
+                }
+                Err(e) => {
+                    eprintln!("Ignoring malformed TCP packet: {:?}", e)
                 }
             }
-            Err(e) => {
-                eprintln!("Ignoring malformed IPv4 packet: {:?}", e)
-            }
+        }
+        Err(e) => {
+            eprintln!("Ignoring malformed IPv4 packet: {:?}", e)
         }
     }
+    Ok(())
 }
 
 impl Interface {
@@ -165,13 +460,27 @@ impl Interface {
             .address((10, 0, 0, 1))
             .netmask((255, 255, 255, 0))
             .up();
-        let mut dev = tun::create(&config).unwrap();
+        let dev = tun::create(&config).unwrap();
+
+        let (wake_rx, wake_tx) = UnixStream::pair()?;
+        wake_rx.set_nonblocking(true)?;
+        wake_tx.set_nonblocking(true)?;
 
-        let ih: InterfaceHandle = Arc::default();
+        let ih: InterfaceHandle = Arc::new(Foobar {
+            manager: Mutex::default(),
+            pending_var: Condvar::new(),
+            rcv_var: Condvar::new(),
+            state_var: Condvar::new(),
+            wake: wake_tx,
+        });
+
+        if let Ok(mtu) = tun::Device::mtu(&dev) {
+            ih.manager.lock().unwrap().config.mtu = mtu as usize;
+        }
 
         let jh = {
             let ih = ih.clone();
-            thread::spawn(move || packet_loop(dev, ih))
+            thread::spawn(move || packet_loop(dev, wake_rx, ih))
         };
 
         Ok(Interface {
@@ -180,13 +489,27 @@ impl Interface {
         })
     }
 
+    /// Listen on `port`, with room for 128 connections waiting for `accept`.
     pub fn bind(&mut self, port: u16) -> io::Result<TcpListener> {
+        self.bind_with_backlog(port, DEFAULT_BACKLOG)
+    }
+
+    /// Listen on `port`, with room for `backlog` connections waiting for `accept`.
+    ///
+    /// Connections only count once their handshake has completed. While the backlog is full, new
+    /// SYNs and the final ACKs of handshakes in progress are dropped, so that peers retry rather
+    /// than fail.
+    pub fn bind_with_backlog(&mut self, port: u16, backlog: usize) -> io::Result<TcpListener> {
         use std::collections::hash_map::Entry;
 
         let mut cm = self.ih.as_mut().unwrap().manager.lock().unwrap();
-        match cm.pending.entry(port) {
+        match cm.listeners.entry(port) {
             Entry::Vacant(v) => {
-                v.insert(VecDeque::new());
+                v.insert(Listener {
+                    syn_queue: HashSet::new(),
+                    accept_queue: VecDeque::new(),
+                    backlog,
+                });
             }
             Entry::Occupied(_) => {
                 return Err(io::Error::new(
@@ -198,11 +521,145 @@ impl Interface {
         drop(cm);
         Ok(TcpListener(port, self.ih.as_mut().unwrap().clone()))
     }
+
+    /// Lock the state shared with the packet loop, e.g. to change the config.
+    fn manager(&self) -> MutexGuard<'_, ConnectionManager> {
+        self.ih.as_ref().unwrap().manager.lock().unwrap()
+    }
+
+    /// Set the maximum segment lifetime for connections created from now on.
+    ///
+    /// Connections we close first stay in TIME-WAIT for twice this long before they are removed.
+    pub fn set_msl(&mut self, msl: time::Duration) {
+        self.manager().config.msl = msl;
+    }
+
+    /// Set how long a connection closed by dropping its `TcpStream` waits for the peer to close.
+    ///
+    /// This only applies once our FIN has been acknowledged; until then the connection keeps
+    /// retransmitting as usual.
+    pub fn set_fin_timeout(&mut self, timeout: time::Duration) {
+        self.manager().config.fin_timeout = timeout;
+    }
+
+    /// Set how long dropping the interface waits for open connections to close.
+    ///
+    /// Connections still open after this are reset. The default of zero resets them right away.
+    pub fn set_shutdown_timeout(&mut self, timeout: time::Duration) {
+        self.manager().config.shutdown_timeout = timeout;
+    }
+
+    /// Set how many bytes of received data each connection created from now on buffers.
+    ///
+    /// This bounds the window advertised to the peer. Buffers over 64KiB need the peer to support
+    /// window scaling; without it the window stays below 64KiB.
+    pub fn set_recv_buffer_size(&mut self, size: usize) {
+        self.manager().config.recv_buffer = size;
+    }
+
+    /// Set the lower bound on the retransmission timeout for connections created from now on.
+    ///
+    /// The default of one second follows RFC 6298; links with a reliably short round-trip time
+    /// recover from loss faster with a lower bound.
+    pub fn set_min_rto(&mut self, rto: time::Duration) {
+        self.manager().config.min_rto = rto;
+    }
+
+    /// Set the congestion control algorithm for connections created from now on.
+    ///
+    /// `new` is called once for every connection, e.g. `set_congestion_control(Cubic::new)`. The
+    /// default is `Reno`.
+    pub fn set_congestion_control<F, C>(&mut self, new: F)
+    where
+        F: Fn() -> C + Send + Sync + 'static,
+        C: CongestionControl + 'static,
+    {
+        self.manager().config.congestion_control = Arc::new(move || Box::new(new()));
+    }
+
+    /// Set how long connections created from now on may hold back the ACK for received data.
+    ///
+    /// Delayed ACKs cover every second full segment anyway, so this mostly bounds how long a lone
+    /// segment waits to be acknowledged. The default is 40ms, and delays beyond 500ms are cut
+    /// short.
+    pub fn set_ack_delay(&mut self, delay: time::Duration) {
+        self.manager().config.ack_delay = delay;
+    }
+
+    /// Set how many half-open connections each listener keeps.
+    ///
+    /// SYNs beyond that are answered with SYN cookies, so that a flood of them can't use up
+    /// memory. The default is 128.
+    pub fn set_syn_backlog(&mut self, backlog: usize) {
+        self.manager().config.syn_backlog = backlog;
+    }
+
+    /// Pick the initial sequence numbers of connections created from now on with `isn`.
+    ///
+    /// `isn` gets the local and the remote address and port. By default they follow RFC 6528,
+    /// which makes them unpredictable; a fixed value makes connections reproducible in tests.
+    pub fn set_isn<F>(&mut self, isn: F)
+    where
+        F: Fn((Ipv4Addr, u16), (Ipv4Addr, u16)) -> u32 + Send + Sync + 'static,
+    {
+        self.manager().config.isn = Arc::new(isn);
+    }
+
+    /// Open a connection to `addr:port`, blocking until the handshake completes.
+    pub fn connect(&mut self, addr: Ipv4Addr, port: u16) -> io::Result<TcpStream> {
+        let ih = self.ih.as_mut().unwrap();
+        let mut cm = ih.manager.lock().unwrap();
+        if cm.terminate {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "interface shut down",
+            ));
+        }
+        let local_port = cm.ephemeral_port().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::AddrNotAvailable, "no free local ports")
+        })?;
+        let quad = Quad {
+            src: (addr, port),
+            dst: (LOCAL_ADDR, local_port),
+        };
+        let c = tcp::Connection::connect(&cm.config, quad.dst, quad.src);
+        cm.connections.insert(quad, c);
+        ih.kick();
+
+        let deadline = time::Instant::now() + CONNECT_TIMEOUT;
+        loop {
+            let c = cm.connections.get(&quad).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::ConnectionAborted, "connection not found")
+            })?;
+
+            match c.state {
+                tcp::State::Closed => {
+                    let kind = c.error.unwrap_or(io::ErrorKind::ConnectionRefused);
+                    cm.connections.remove(&quad);
+                    return Err(kind.into());
+                }
+                s if s.is_synchronized() => return Ok(TcpStream(quad, ih.clone())),
+                _ => {}
+            }
+
+            let now = time::Instant::now();
+            if now >= deadline {
+                cm.connections.remove(&quad);
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "connection timed out",
+                ));
+            }
+            cm = ih.state_var.wait_timeout(cm, deadline - now).unwrap().0;
+        }
+    }
 }
 
 impl Drop for Interface {
     fn drop(&mut self) {
-        self.ih.as_mut().unwrap().manager.lock().unwrap().terminate = true;
+        let ih = self.ih.as_mut().unwrap();
+        ih.manager.lock().unwrap().terminate = true;
+        ih.kick();
         drop(self.ih.take());
         self.ih = None;
         self.jh.take().unwrap().join().unwrap().unwrap();
@@ -213,11 +670,20 @@ pub struct TcpStream(Quad, InterfaceHandle);
 
 impl Drop for TcpStream {
     fn drop(&mut self) {
+        // like closing a socket in the kernel: anything still queued is sent followed by a FIN,
+        // and the connection stays around as an orphan until the close handshake is done
         let mut cm = self.1.manager.lock().unwrap();
-        // if let Some(c) = cm.connections.remove(&self.0) {
-        //     // TODO: Send FIN
-        //     unimplemented!()
-        // }
+        if let Some(c) = cm.connections.get_mut(&self.0) {
+            // an error only means there is nothing left to close
+            let _ = c.close();
+            c.orphaned = true;
+            c.incoming.clear();
+            if c.is_reapable() {
+                cm.connections.remove(&self.0);
+            }
+        }
+        drop(cm);
+        self.1.kick();
     }
 }
 
@@ -229,6 +695,10 @@ impl Read for TcpStream {
                 io::Error::new(io::ErrorKind::ConnectionAborted, "connection not found")
             })?;
 
+            if let Some(kind) = c.error {
+                return Err(kind.into());
+            }
+
             if c.is_rcv_closed() && c.incoming.is_empty() {
                 // no more data to read and connection is closed, no need to block
                 return Ok(0);
@@ -244,6 +714,9 @@ impl Read for TcpStream {
                 buf[hread..(hread + tread)].copy_from_slice(&tail[..tread]);
                 nread += tread;
                 drop(c.incoming.drain(..nread));
+                if c.window_update_due() {
+                    self.1.kick();
+                }
                 return Ok(nread);
             }
 
@@ -259,6 +732,17 @@ impl Write for TcpStream {
             io::Error::new(io::ErrorKind::ConnectionAborted, "connection not found")
         })?;
 
+        if let Some(kind) = c.error {
+            return Err(kind.into());
+        }
+
+        if c.closed {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection closed for writing",
+            ));
+        }
+
         if c.unacked.len() >= SENDQUEUE_SIZE {
             // TODO: block
             return Err(io::Error::new(
@@ -269,6 +753,9 @@ impl Write for TcpStream {
 
         let nwrite = std::cmp::min(buf.len(), SENDQUEUE_SIZE - c.unacked.len());
         c.unacked.extend(buf[..nwrite].iter());
+        drop(cm);
+        // get the data on the wire now rather than at the next tick
+        self.1.kick();
         Ok(nwrite)
     }
 
@@ -278,8 +765,12 @@ impl Write for TcpStream {
             io::Error::new(io::ErrorKind::ConnectionAborted, "connection not found")
         })?;
 
+        if let Some(kind) = c.error {
+            return Err(kind.into());
+        }
+
         if c.unacked.is_empty() {
-            return Ok(());
+            Ok(())
         } else {
             // TODO: block
             Err(io::Error::new(
@@ -291,27 +782,81 @@ impl Write for TcpStream {
 }
 
 impl TcpStream {
-    pub fn shutdown(&self, how: std::net::Shutdown) -> io::Result<()> {
+    pub fn shutdown(&self, _how: std::net::Shutdown) -> io::Result<()> {
         let mut cm = self.1.manager.lock().unwrap();
         let c = cm.connections.get_mut(&self.0).ok_or_else(|| {
             io::Error::new(io::ErrorKind::ConnectionAborted, "connection not found")
         })?;
 
-        c.closed = true;
-        match c.state {
-            tcp::State::SynRcvd | tcp::State::Estab => {
-                c.state = tcp::State::FinWait1;
-            }
-            tcp::State::FinWait1 | tcp::State::FinWait2 => {}
-            _ => {
-                return Err(io::Error::new(
-                    io::ErrorKind::NotConnected,
-                    "connection not established",
-                ));
-            }
+        if let Some(kind) = c.error {
+            return Err(kind.into());
         }
+
+        c.close()?;
+        drop(cm);
+        self.1.kick();
         Ok(())
     }
+
+    /// Switch this connection to another congestion control algorithm.
+    ///
+    /// The new algorithm starts out from its initial window.
+    pub fn set_congestion_control<C: CongestionControl + 'static>(
+        &self,
+        controller: C,
+    ) -> io::Result<()> {
+        self.with_connection(|c| c.set_congestion_control(Box::new(controller)))
+    }
+
+    /// Disable Nagle's algorithm, so that small writes are sent right away even while earlier
+    /// data is unacknowledged.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        self.with_connection(|c| c.nodelay = nodelay)?;
+        // anything held back can go out now
+        self.1.kick();
+        Ok(())
+    }
+
+    /// Whether Nagle's algorithm is disabled for this connection.
+    pub fn nodelay(&self) -> io::Result<bool> {
+        self.with_connection(|c| c.nodelay)
+    }
+
+    /// Probe the peer once the connection has been idle for a while, or stop probing with `None`.
+    ///
+    /// If `count` probes in a row go unanswered, the connection fails with `TimedOut`.
+    pub fn set_keepalive(&self, keepalive: Option<KeepaliveConfig>) -> io::Result<()> {
+        self.with_connection(|c| c.keepalive = keepalive)
+    }
+
+    /// How this connection probes an idle peer, if it does.
+    pub fn keepalive(&self) -> io::Result<Option<KeepaliveConfig>> {
+        self.with_connection(|c| c.keepalive)
+    }
+
+    /// Acknowledge every segment from the peer as soon as it arrives instead of delaying ACKs.
+    pub fn set_quickack(&self, quickack: bool) -> io::Result<()> {
+        self.with_connection(|c| c.quickack = quickack)
+    }
+
+    /// Whether this connection acknowledges every segment right away.
+    pub fn quickack(&self) -> io::Result<bool> {
+        self.with_connection(|c| c.quickack)
+    }
+
+    /// The current retransmission timeout of this connection, including any backoff.
+    pub fn rto(&self) -> io::Result<time::Duration> {
+        self.with_connection(|c| c.rto())
+    }
+
+    /// Run `f` on this stream's connection, which is gone once the interface has reaped it.
+    fn with_connection<R>(&self, f: impl FnOnce(&mut tcp::Connection) -> R) -> io::Result<R> {
+        let mut cm = self.1.manager.lock().unwrap();
+        let c = cm.connections.get_mut(&self.0).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::ConnectionAborted, "connection not found")
+        })?;
+        Ok(f(c))
+    }
 }
 
 pub struct TcpListener(u16, InterfaceHandle);
@@ -319,14 +864,20 @@ pub struct TcpListener(u16, InterfaceHandle);
 impl Drop for TcpListener {
     fn drop(&mut self) {
         let mut cm = self.1.manager.lock().unwrap();
-        let pending = cm
-            .pending
+        let listener = cm
+            .listeners
             .remove(&self.0)
             .expect("port closed while listener active");
 
-        for quad in pending {
-            unimplemented!()
+        // nobody is going to accept these anymore, so reset them like the kernel does
+        for quad in listener.syn_queue.iter().chain(&listener.accept_queue) {
+            if let Some(c) = cm.connections.get_mut(quad) {
+                c.orphaned = true;
+                c.abort();
+            }
         }
+        drop(cm);
+        self.1.kick();
     }
 }
 
@@ -334,11 +885,367 @@ impl TcpListener {
     pub fn accept(&mut self) -> io::Result<TcpStream> {
         let mut cm = self.1.manager.lock().unwrap();
         loop {
-            if let Some(quad) = cm.pending.get_mut(&self.0).unwrap().pop_front() {
-                return Ok(TcpStream(quad, self.1.clone()));
+            if cm.terminate {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "interface shut down",
+                ));
+            }
+
+            while let Some(quad) = cm
+                .listeners
+                .get_mut(&self.0)
+                .unwrap()
+                .accept_queue
+                .pop_front()
+            {
+                // skip connections that were reset before we got to them
+                if cm.connections.contains_key(&quad) {
+                    return Ok(TcpStream(quad, self.1.clone()));
+                }
             }
 
             cm = self.1.pending_var.wait(cm).unwrap();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mock::{IRS, ISS};
+
+    /// An interface without a device; tests play the packet loop with `on_tick` and
+    /// `on_datagram`.
+    struct Fixture {
+        interface: Interface,
+        nic: mock::Device,
+        shutdown_started: Option<time::Instant>,
+        _wake: UnixStream,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            let (wake_rx, wake_tx) = UnixStream::pair().unwrap();
+            wake_tx.set_nonblocking(true).unwrap();
+            let ih: InterfaceHandle = Arc::new(Foobar {
+                manager: Mutex::default(),
+                pending_var: Condvar::new(),
+                rcv_var: Condvar::new(),
+                state_var: Condvar::new(),
+                wake: wake_tx,
+            });
+            let mut interface = Interface {
+                ih: Some(ih),
+                jh: Some(thread::spawn(|| Ok(()))),
+            };
+            interface.set_isn(|_, _| ISS);
+            Fixture {
+                interface,
+                nic: mock::Device::default(),
+                shutdown_started: None,
+                _wake: wake_rx,
+            }
+        }
+
+        fn ih(&self) -> &InterfaceHandle {
+            self.interface.ih.as_ref().unwrap()
+        }
+
+        /// Deliver the segment `builder` describes, carrying `payload`.
+        fn deliver(
+            &mut self,
+            builder: etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+            payload: &[u8],
+        ) {
+            let datagram = mock::datagram(builder, payload);
+            let ih = self.interface.ih.as_ref().unwrap();
+            on_datagram(&mut self.nic, ih, &datagram).unwrap();
+        }
+
+        fn tick(&mut self) {
+            let ih = self.interface.ih.as_ref().unwrap();
+            let done = on_tick(&mut self.nic, ih, &mut self.shutdown_started).unwrap();
+            assert!(!done);
+        }
+
+        /// Tick as the packet loop does once the interface is dropped, returning whether it
+        /// stopped.
+        fn tick_shutdown(&mut self) -> bool {
+            let ih = self.interface.ih.as_ref().unwrap();
+            ih.manager.lock().unwrap().terminate = true;
+            let done = on_tick(&mut self.nic, ih, &mut self.shutdown_started).unwrap();
+            if done {
+                loop_stopped(ih);
+            }
+            done
+        }
+
+        fn connections(&self) -> usize {
+            self.ih().manager.lock().unwrap().connections.len()
+        }
+    }
+
+    /// Answer the peer's SYN, and check the SYN,ACK.
+    fn handshake_started(f: &mut Fixture) {
+        f.deliver(mock::segment(IRS).syn(), &[]);
+        let sent = f.nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (syn_ack, _) = &sent[0];
+        assert!(syn_ack.syn && syn_ack.ack);
+        assert_eq!(syn_ack.sequence_number, ISS);
+        assert_eq!(syn_ack.acknowledgment_number, IRS + 1);
+        assert_eq!(f.connections(), 1);
+    }
+
+    /// Complete the handshake with the peer and accept the connection.
+    fn established(f: &mut Fixture, listener: &mut TcpListener) -> TcpStream {
+        handshake_started(f);
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        listener.accept().unwrap()
+    }
+
+    /// Check that dropping the listener reset the connection and freed the port.
+    fn reset_and_unbound(f: &mut Fixture) {
+        f.tick();
+        let sent = f.nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (rst, _) = &sent[0];
+        assert!(rst.rst);
+        assert_eq!(rst.sequence_number, ISS + 1);
+        assert_eq!(f.connections(), 0);
+        assert!(f.interface.bind(80).is_ok());
+    }
+
+    #[test]
+    fn drop_listener_during_handshake() {
+        let mut f = Fixture::new();
+        let listener = f.interface.bind(80).unwrap();
+        handshake_started(&mut f);
+
+        drop(listener);
+        reset_and_unbound(&mut f);
+    }
+
+    #[test]
+    fn drop_listener_before_accept() {
+        let mut f = Fixture::new();
+        let listener = f.interface.bind(80).unwrap();
+        handshake_started(&mut f);
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        assert!(f.nic.take_segments().is_empty());
+        assert_eq!(
+            f.ih().manager.lock().unwrap().listeners[&80]
+                .accept_queue
+                .len(),
+            1
+        );
+
+        drop(listener);
+        reset_and_unbound(&mut f);
+    }
+
+    #[test]
+    fn handshake_completes_after_listener_dropped() {
+        let mut f = Fixture::new();
+        let listener = f.interface.bind(80).unwrap();
+        handshake_started(&mut f);
+        drop(listener);
+        f.tick();
+        f.nic.take_segments();
+
+        // the peer's ACK crossed our RST, and nothing is left to take it
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        let sent = f.nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (rst, _) = &sent[0];
+        assert!(rst.rst);
+        assert_eq!(rst.sequence_number, ISS + 1);
+        assert_eq!(f.connections(), 0);
+    }
+
+    #[test]
+    fn accept_skips_handshakes_in_progress() {
+        let mut f = Fixture::new();
+        let mut listener = f.interface.bind(80).unwrap();
+        handshake_started(&mut f);
+        let other = mock::REMOTE_PORT + 1;
+        f.deliver(mock::segment_from(other, IRS).syn(), &[]);
+        f.deliver(mock::segment_from(other, IRS + 1).ack(ISS + 1), &[]);
+        f.nic.take_segments();
+
+        let stream = listener.accept().unwrap();
+        assert_eq!(stream.0.src.1, other);
+        let cm = f.ih().manager.lock().unwrap();
+        assert_eq!(cm.connections[&stream.0].state, tcp::State::Estab);
+        assert!(cm.listeners[&80].accept_queue.is_empty());
+        drop(cm);
+
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        let stream = listener.accept().unwrap();
+        assert_eq!(stream.0.src.1, mock::REMOTE_PORT);
+    }
+
+    #[test]
+    fn full_backlog_drops_syn_and_final_ack() {
+        let mut f = Fixture::new();
+        let mut listener = f.interface.bind_with_backlog(80, 1).unwrap();
+        handshake_started(&mut f);
+        let other = mock::REMOTE_PORT + 1;
+        f.deliver(mock::segment_from(other, IRS).syn(), &[]);
+        f.deliver(mock::segment_from(other, IRS + 1).ack(ISS + 1), &[]);
+        f.nic.take_segments();
+
+        // no answer to a new SYN, and the first handshake stays unfinished
+        f.deliver(mock::segment_from(other + 1, IRS).syn(), &[]);
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        assert!(f.nic.take_segments().is_empty());
+        assert_eq!(f.connections(), 2);
+        let q = Quad {
+            src: (mock::REMOTE.into(), mock::REMOTE_PORT),
+            dst: (mock::LOCAL.into(), 80),
+        };
+        assert_eq!(
+            f.ih().manager.lock().unwrap().connections[&q].state,
+            tcp::State::SynRcvd
+        );
+
+        // once there is room again, the peer's repeated ACK gets it accepted
+        assert_eq!(listener.accept().unwrap().0.src.1, other);
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        assert_eq!(listener.accept().unwrap().0, q);
+    }
+
+    #[test]
+    fn shutdown_without_timeout_resets() {
+        let mut f = Fixture::new();
+        let mut listener = f.interface.bind(80).unwrap();
+        let mut stream = established(&mut f, &mut listener);
+        stream.write_all(b"hello").unwrap();
+
+        assert!(f.tick_shutdown());
+        let sent = f.nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (rst, data) = &sent[0];
+        assert!(rst.rst);
+        assert_eq!(rst.sequence_number, ISS + 1);
+        assert!(data.is_empty());
+        assert_eq!(
+            stream.read(&mut [0; 8]).unwrap_err().kind(),
+            io::ErrorKind::ConnectionAborted
+        );
+    }
+
+    #[test]
+    fn shutdown_with_timeout_sends_fin() {
+        let mut f = Fixture::new();
+        f.interface
+            .set_shutdown_timeout(time::Duration::from_secs(10));
+        let mut listener = f.interface.bind(80).unwrap();
+        let mut stream = established(&mut f, &mut listener);
+        stream.write_all(b"hello").unwrap();
+
+        assert!(!f.tick_shutdown());
+        let sent = f.nic.take_segments();
+        assert!(sent.iter().all(|(tcp, _)| !tcp.rst));
+        let data: Vec<u8> = sent.iter().flat_map(|(_, data)| data.clone()).collect();
+        assert_eq!(data, b"hello");
+        // the FIN comes after the data
+        let (fin, data) = sent.last().unwrap();
+        assert!(fin.fin);
+        assert_eq!(fin.sequence_number + data.len() as u32, ISS + 6);
+
+        // the peer acknowledges everything and closes too
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 7), &[]);
+        f.deliver(mock::segment(IRS + 1).ack(ISS + 7).fin(), &[]);
+        f.nic.take_segments();
+        assert!(f.tick_shutdown());
+        assert!(f.nic.take_segments().iter().all(|(tcp, _)| !tcp.rst));
+        assert_eq!(stream.read(&mut [0; 8]).unwrap(), 0);
+    }
+
+    #[test]
+    fn shutdown_wakes_blocked_calls() {
+        let mut f = Fixture::new();
+        let mut listener = f.interface.bind(80).unwrap();
+        let mut stream = established(&mut f, &mut listener);
+        let reader = thread::spawn(move || stream.read(&mut [0; 8]));
+        let acceptor = thread::spawn(move || listener.accept().map(|_| ()));
+        // give both a chance to block first
+        thread::sleep(time::Duration::from_millis(50));
+
+        assert!(f.tick_shutdown());
+        let err = reader.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        let err = acceptor.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+    }
+
+    /// Deliver a segment to a port nobody listens on, and return the RST that answers it.
+    fn closed_port_rst(
+        builder: etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+        payload: &[u8],
+    ) -> etherparse::TcpHeader {
+        let mut f = Fixture::new();
+        f.deliver(builder, payload);
+        let mut sent = f.nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (rst, data) = sent.pop().unwrap();
+        assert!(rst.rst && !rst.syn && !rst.fin);
+        assert!(data.is_empty());
+        assert_eq!(
+            (rst.source_port, rst.destination_port),
+            (mock::LOCAL_PORT, mock::REMOTE_PORT)
+        );
+        assert_eq!(f.connections(), 0);
+        rst
+    }
+
+    #[test]
+    fn rst_for_syn_with_data_to_closed_port() {
+        let rst = closed_port_rst(mock::segment(IRS).syn(), b"hello");
+        // without an ACK to take it from, the RST has sequence number zero and acknowledges
+        // the SYN and the data
+        assert_eq!(rst.sequence_number, 0);
+        assert!(rst.ack);
+        assert_eq!(rst.acknowledgment_number, IRS + 1 + 5);
+    }
+
+    #[test]
+    fn rst_for_fin_to_closed_port() {
+        let rst = closed_port_rst(mock::segment(IRS).fin(), b"bye");
+        assert_eq!(rst.sequence_number, 0);
+        assert!(rst.ack);
+        assert_eq!(rst.acknowledgment_number, IRS + 3 + 1);
+    }
+
+    #[test]
+    fn rst_for_ack_to_closed_port() {
+        let rst = closed_port_rst(mock::segment(IRS).ack(ISS), b"hello");
+        // the peer's ACK tells it which sequence number to accept
+        assert_eq!(rst.sequence_number, ISS);
+        assert!(!rst.ack);
+    }
+
+    #[test]
+    fn connect_error_describes_its_kind() {
+        let mut f = Fixture::new();
+        let ih = f.ih().clone();
+        let stopper = thread::spawn(move || {
+            while ih.manager.lock().unwrap().connections.is_empty() {
+                thread::sleep(time::Duration::from_millis(1));
+            }
+            loop_stopped(&ih);
+        });
+
+        let Err(err) = f.interface.connect(mock::REMOTE.into(), mock::REMOTE_PORT) else {
+            panic!("connected after the interface shut down");
+        };
+        stopper.join().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(
+            err.to_string(),
+            io::Error::from(io::ErrorKind::ConnectionAborted).to_string()
+        );
+    }
+}
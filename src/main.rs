@@ -8,7 +8,7 @@ fn main() -> io::Result<()> {
 
     while let Ok(mut stream) = listener.accept() {
         thread::spawn(move || {
-            stream.write(b"Hello World").unwrap();
+            stream.write_all(b"Hello World").unwrap();
             stream.shutdown(std::net::Shutdown::Write).unwrap();
             loop {
                 let mut buf = [0; 512];
@@ -0,0 +1,156 @@
+use etherparse::{PacketBuilder, PacketBuilderStep, TcpHeader};
+use std::io::{self, Read, Write};
+use std::net::Ipv4Addr;
+
+/// Our address in tests.
+pub(crate) const LOCAL: [u8; 4] = [10, 0, 0, 2];
+/// The peer's address in tests.
+pub(crate) const REMOTE: [u8; 4] = [10, 0, 0, 1];
+/// The port the peer connects to.
+pub(crate) const LOCAL_PORT: u16 = 80;
+/// The port the peer connects from.
+pub(crate) const REMOTE_PORT: u16 = 40000;
+/// The sequence number of the peer's SYN.
+pub(crate) const IRS: u32 = 1000;
+/// The sequence number tests pick for our SYN.
+pub(crate) const ISS: u32 = 5000;
+
+/// A `tun::Device` that keeps the packets written to it instead of sending them anywhere.
+#[derive(Default)]
+pub(crate) struct Device {
+    /// packets in the order they were written, including the packet information header
+    pub(crate) sent: Vec<Vec<u8>>,
+}
+
+impl Device {
+    /// The TCP segments written so far, which are removed from the device.
+    pub(crate) fn take_segments(&mut self) -> Vec<(etherparse::TcpHeader, Vec<u8>)> {
+        self.sent
+            .drain(..)
+            .map(|packet| {
+                let (_, tcp, data) = headers(&packet[4..]);
+                (tcp.to_header(), data.to_vec())
+            })
+            .collect()
+    }
+}
+
+impl Read for Device {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Err(io::ErrorKind::WouldBlock.into())
+    }
+}
+
+impl Write for Device {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sent.push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl tun::Device for Device {
+    type Queue = tun::platform::Queue;
+
+    fn name(&self) -> tun::Result<String> {
+        Ok("mock".into())
+    }
+
+    fn set_name(&mut self, _name: &str) -> tun::Result<()> {
+        Ok(())
+    }
+
+    fn enabled(&mut self, _value: bool) -> tun::Result<()> {
+        Ok(())
+    }
+
+    fn address(&self) -> tun::Result<Ipv4Addr> {
+        Ok(Ipv4Addr::new(10, 0, 0, 1))
+    }
+
+    fn set_address(&mut self, _value: Ipv4Addr) -> tun::Result<()> {
+        Ok(())
+    }
+
+    fn destination(&self) -> tun::Result<Ipv4Addr> {
+        Ok(Ipv4Addr::UNSPECIFIED)
+    }
+
+    fn set_destination(&mut self, _value: Ipv4Addr) -> tun::Result<()> {
+        Ok(())
+    }
+
+    fn broadcast(&self) -> tun::Result<Ipv4Addr> {
+        Ok(Ipv4Addr::UNSPECIFIED)
+    }
+
+    fn set_broadcast(&mut self, _value: Ipv4Addr) -> tun::Result<()> {
+        Ok(())
+    }
+
+    fn netmask(&self) -> tun::Result<Ipv4Addr> {
+        Ok(Ipv4Addr::new(255, 255, 255, 0))
+    }
+
+    fn set_netmask(&mut self, _value: Ipv4Addr) -> tun::Result<()> {
+        Ok(())
+    }
+
+    fn mtu(&self) -> tun::Result<i32> {
+        Ok(1500)
+    }
+
+    fn set_mtu(&mut self, _value: i32) -> tun::Result<()> {
+        Ok(())
+    }
+
+    fn queue(&mut self, _index: usize) -> Option<&mut Self::Queue> {
+        None
+    }
+}
+
+/// A segment from the peer's port to ours with sequence number `seq` and a 64KiB window, to set
+/// flags and options on.
+pub(crate) fn segment(seq: u32) -> PacketBuilderStep<TcpHeader> {
+    segment_from(REMOTE_PORT, seq)
+}
+
+/// Like `segment`, for a second connection from the peer's `port`.
+pub(crate) fn segment_from(port: u16, seq: u32) -> PacketBuilderStep<TcpHeader> {
+    PacketBuilder::ipv4(REMOTE, LOCAL, 64).tcp(port, LOCAL_PORT, seq, u16::MAX)
+}
+
+/// Build an IP packet carrying `payload` in the segment `builder` describes.
+pub(crate) fn packet(builder: PacketBuilderStep<TcpHeader>, payload: &[u8]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(builder.size(payload.len()));
+    builder
+        .write(&mut packet, payload)
+        .expect("segment fits in a packet");
+    packet
+}
+
+/// The same packet as the device hands it to the packet loop, behind the packet information
+/// header.
+pub(crate) fn datagram(builder: PacketBuilderStep<TcpHeader>, payload: &[u8]) -> Vec<u8> {
+    let mut datagram = vec![0, 0, 0, 2];
+    datagram.extend(packet(builder, payload));
+    datagram
+}
+
+/// Split an IP packet into its headers and payload, the way `on_packet` takes them.
+pub(crate) fn headers(
+    packet: &[u8],
+) -> (
+    etherparse::Ipv4HeaderSlice<'_>,
+    etherparse::TcpHeaderSlice<'_>,
+    &[u8],
+) {
+    let ip = etherparse::Ipv4HeaderSlice::from_slice(packet).expect("valid IPv4 header");
+    let tcp = etherparse::TcpHeaderSlice::from_slice(&packet[ip.slice().len()..])
+        .expect("valid TCP header");
+    let data = &packet[ip.slice().len() + tcp.slice().len()..];
+    (ip, tcp, data)
+}
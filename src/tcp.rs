@@ -1,8 +1,58 @@
 use bitflags::bitflags;
 use std::collections::{BTreeMap, VecDeque};
+use std::hash::{BuildHasher, RandomState};
 use std::io::Write;
+use std::net::Ipv4Addr;
+use std::sync::Arc;
 use std::{io, time};
 
+mod congestion;
+mod options;
+mod reassembly;
+mod scoreboard;
+mod syncookie;
+
+/// The MSS to assume for the peer (RFC 1122 S4.2.2.6).
+const DEFAULT_MSS: usize = 536;
+
+/// The size of the IPv4 and TCP headers without options, which the MSS leaves out (RFC 6691).
+const HEADERS_LEN: usize = 40;
+
+/// The largest window scale shift allowed (RFC 7323 S2.3).
+const MAX_WINDOW_SHIFT: u8 = 14;
+
+/// How long a timestamp from the peer stays valid for PAWS (RFC 7323 S5.5).
+const PAWS_IDLE: time::Duration = time::Duration::from_secs(24 * 24 * 60 * 60);
+
+/// The smallest MSS we take from a peer, as Linux does, which leaves room for data after a full
+/// 40 bytes of options.
+const MIN_MSS: usize = 88;
+
+/// The retransmission timeout before we have measured the round-trip time (RFC 6298 S2.1).
+const RTO_INITIAL: time::Duration = time::Duration::from_secs(1);
+/// The initial RTO to fall back to if the SYN or SYN,ACK had to be retransmitted (RFC 6298 S5.7).
+const RTO_AFTER_SYN_LOSS: time::Duration = time::Duration::from_secs(3);
+/// The upper bound on the RTO, also used for the interval between zero window probes.
+const RTO_MAX: time::Duration = time::Duration::from_secs(60);
+/// How many times a passive open resends its SYN,ACK before giving up on the handshake, as
+/// Linux does by default.
+const SYNACK_RETRIES: u32 = 5;
+/// How many times in a row the retransmission timer may expire before we give up on the peer
+/// (R2 in RFC 1122 S4.2.3.5), as Linux does by default.
+const RETRIES: u32 = 15;
+/// How precisely our timers fire; the packet loop ticks at least this often.
+const CLOCK_GRANULARITY: time::Duration = time::Duration::from_millis(10);
+/// The longest we hold back an ACK, whatever the configured delay (RFC 1122 S4.2.3.2).
+const MAX_ACK_DELAY: time::Duration = time::Duration::from_millis(500);
+
+use congestion::Congestion;
+pub use congestion::{CongestionControl, Cubic, Reno};
+use etherparse::TcpOptionElement;
+use options::Options;
+use reassembly::Reassembly;
+use scoreboard::Scoreboard;
+pub(crate) use syncookie::SynCookies;
+
 bitflags! {
     pub(crate) struct Available: u8 {
         const READ = 0b00000001;
@@ -10,75 +60,286 @@ bitflags! {
     }
 }
 
+/// Picks the initial sequence number for a connection from its local and remote address.
+pub(crate) type Isn = dyn Fn((Ipv4Addr, u16), (Ipv4Addr, u16)) -> u32 + Send + Sync;
+
+/// Per-connection settings, copied from the `Interface` when a connection is created.
+#[derive(Clone)]
+pub(crate) struct Config {
+    /// maximum segment lifetime; closed connections linger in TIME-WAIT for twice this long
+    pub(crate) msl: time::Duration,
+    /// how long a connection whose `TcpStream` is gone waits in FIN-WAIT-2 for the peer's FIN
+    pub(crate) fin_timeout: time::Duration,
+    /// how long dropping the `Interface` waits for connections to close before resetting them
+    pub(crate) shutdown_timeout: time::Duration,
+    /// how much received data we buffer for the application, which bounds the window we advertise
+    pub(crate) recv_buffer: usize,
+    /// lower bound on the retransmission timeout
+    pub(crate) min_rto: time::Duration,
+    /// MTU of the device, which bounds the segments we send and the MSS we advertise
+    pub(crate) mtu: usize,
+    /// creates the congestion control algorithm for each connection
+    pub(crate) congestion_control: Arc<dyn Fn() -> Box<dyn CongestionControl> + Send + Sync>,
+    /// how long we may hold back the ACK for received data (RFC 1122 S4.2.3.2)
+    pub(crate) ack_delay: time::Duration,
+    /// picks the initial sequence number for each connection
+    pub(crate) isn: Arc<Isn>,
+    /// how many half-open connections a listener keeps before it answers SYNs with SYN cookies
+    pub(crate) syn_backlog: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            msl: time::Duration::from_secs(30),
+            fin_timeout: time::Duration::from_secs(60),
+            shutdown_timeout: time::Duration::ZERO,
+            recv_buffer: u16::MAX as usize,
+            min_rto: time::Duration::from_secs(1),
+            mtu: 1500,
+            congestion_control: Arc::new(|| Box::new(Reno::new())),
+            ack_delay: time::Duration::from_millis(40),
+            isn: isn_generator(),
+            syn_backlog: 128,
+        }
+    }
+}
+
+/// Initial sequence numbers as RFC 6528 suggests: a clock that ticks every 4 microseconds plus a
+/// keyed hash of the connection's addresses.
+///
+/// The clock keeps a quad's sequence numbers moving forward from one connection to the next, and
+/// the key, which is different for every generator, keeps them hard to guess from off the path.
+fn isn_generator() -> Arc<Isn> {
+    let secret = RandomState::new();
+    let clock = time::Instant::now();
+    Arc::new(move |local, remote| {
+        let ticks = (clock.elapsed().as_micros() / 4) as u32;
+        ticks.wrapping_add(secret.hash_one((local, remote)) as u32)
+    })
+}
+
+/// When to probe an idle connection to find out whether the peer is still there (RFC 1122
+/// S4.2.3.6).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct KeepaliveConfig {
+    /// how long the connection has to be idle before the first probe
+    pub idle: time::Duration,
+    /// how long to wait for an answer before the next probe
+    pub interval: time::Duration,
+    /// how many unanswered probes it takes to give up on the connection
+    pub count: u32,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        KeepaliveConfig {
+            idle: time::Duration::from_secs(2 * 60 * 60),
+            interval: time::Duration::from_secs(75),
+            count: 9,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum State {
+    Closed,
+    SynSent,
     SynRcvd,
     Estab,
     FinWait1,
     FinWait2,
     Closing,
     TimeWait,
+    CloseWait,
+    LastAck,
 }
 
 impl State {
-    fn is_synchronized(&self) -> bool {
+    pub(crate) fn is_synchronized(&self) -> bool {
         match self {
-            State::SynRcvd => false,
-            State::Estab | State::FinWait1 | State::Closing | State::FinWait2 | State::TimeWait => {
-                true
-            }
+            State::Closed | State::SynSent | State::SynRcvd => false,
+            State::Estab
+            | State::FinWait1
+            | State::Closing
+            | State::FinWait2
+            | State::TimeWait
+            | State::CloseWait
+            | State::LastAck => true,
         }
     }
 
-    fn have_sent_fin(&self) -> bool {
+    pub(crate) fn have_sent_fin(&self) -> bool {
         match self {
-            State::SynRcvd | State::Estab => false,
-            State::FinWait1 | State::Closing | State::FinWait2 | State::TimeWait => true,
+            State::Closed | State::SynSent | State::SynRcvd | State::Estab | State::CloseWait => {
+                false
+            }
+            State::FinWait1
+            | State::Closing
+            | State::FinWait2
+            | State::TimeWait
+            | State::LastAck => true,
         }
     }
 }
 
 pub struct Connection {
     pub(crate) state: State,
+    config: Config,
     send: SendSequenceSpace,
     recv: ReceiveSequenceSpace,
     ip: etherparse::Ipv4Header,
     tcp: etherparse::TcpHeader,
 
     timers: Timers,
+    congestion: Congestion,
 
     pub(crate) incoming: VecDeque<u8>,
+    reassembly: Reassembly,
     pub(crate) unacked: VecDeque<u8>,
     pub(crate) closed: bool,
     closed_at: Option<u32>,
+    pub(crate) error: Option<io::ErrorKind>,
+    /// the `TcpStream` for this connection has been dropped
+    pub(crate) orphaned: bool,
+    /// we got here from a listening port rather than `Interface::connect`
+    passive: bool,
+    /// `abort` was called; the RST goes out on the next tick
+    aborting: bool,
+    /// the peer has acknowledged our SYN, so SND.UNA is data or our FIN rather than the SYN
+    syn_acked: bool,
+    /// both sides can use SACK; until the peer's SYN arrives, whether we offer it
+    sack_permitted: bool,
+    /// both sides scale their windows; until the peer's SYN arrives, whether we offer it
+    window_scaling: bool,
+    /// both sides send timestamps; until the peer's SYN arrives, whether we offer them
+    timestamps: Option<Timestamps>,
+    /// the peer's SACK blocks, which tell us what to retransmit in recovery
+    scoreboard: Scoreboard,
+    /// where to look for the next hole to retransmit in recovery (HighRxt in RFC 6675)
+    rtx_next: u32,
+    /// the largest segment we send before options, which is the peer's MSS capped by our MTU
+    mss: u32,
+    /// the largest segment the peer has sent us, which is what counts as a full segment
+    rcv_mss: u32,
+    /// bytes received since we last sent an ACK
+    ack_pending: u32,
+    /// acknowledge received data right away instead of delaying the ACK
+    pub(crate) quickack: bool,
+    /// send small segments right away instead of coalescing them with Nagle's algorithm
+    pub(crate) nodelay: bool,
+    /// probe the peer once the connection has been idle for a while
+    pub(crate) keepalive: Option<KeepaliveConfig>,
+}
+
+/// Timestamps option state (RFC 7323 S4.3, S5.3).
+struct Timestamps {
+    /// when our timestamp clock started
+    base: time::Instant,
+    /// the peer's timestamp to echo (TS.Recent)
+    recent: u32,
+    /// when `recent` was last updated
+    recent_at: time::Instant,
+    /// the ACK number in the last segment we sent (Last.ACK.sent)
+    last_ack_sent: u32,
+}
+
+impl Timestamps {
+    /// Our timestamp clock, which ticks every millisecond.
+    ///
+    /// It starts at one so that an echo of zero always means the peer had nothing to echo.
+    fn now(&self) -> u32 {
+        (self.base.elapsed().as_millis() as u32).wrapping_add(1)
+    }
 }
 
 struct Timers {
-    send_times: BTreeMap<u32, time::Instant>,
-    srtt: f64,
+    /// when each segment still in flight was last sent, and whether that was a retransmission
+    send_times: BTreeMap<u32, (time::Instant, bool)>,
+    /// smoothed round-trip time, once we have a sample
+    srtt: Option<time::Duration>,
+    /// round-trip time variation
+    rttvar: time::Duration,
+    /// retransmission timeout, before backing off
+    rto: time::Duration,
+    /// number of retransmission timeouts since the last RTT sample
+    backoff: u32,
+    /// when the retransmission timer expires, if anything is in flight
+    retransmit: Option<time::Instant>,
+    /// when we (last) entered TIME-WAIT
+    time_wait: Option<time::Instant>,
+    /// when our FIN was acknowledged
+    fin_wait2: Option<time::Instant>,
+    /// when to send the next zero window probe
+    persist: Option<time::Instant>,
+    /// number of probes sent since the peer's window closed
+    persist_backoff: u32,
+    /// when the ACK we are holding back has to go out
+    delayed_ack: Option<time::Instant>,
+    /// when we last got an acceptable segment from the peer
+    last_recv: time::Instant,
+    /// number of keepalive probes sent since then
+    keepalive_probes: u32,
+}
+
+impl Timers {
+    /// The retransmission timeout including backoff.
+    fn rto(&self) -> time::Duration {
+        std::cmp::min(
+            self.rto.saturating_mul(2u32.saturating_pow(self.backoff)),
+            RTO_MAX,
+        )
+    }
+
+    /// Fold a round-trip time measurement into the RTO estimate (RFC 6298 S2).
+    fn sample(&mut self, rtt: time::Duration, min_rto: time::Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                self.rttvar = (3 * self.rttvar + srtt.abs_diff(rtt)) / 4;
+                self.srtt = Some((7 * srtt + rtt) / 8);
+            }
+        }
+        let rto = self.srtt.unwrap() + std::cmp::max(CLOCK_GRANULARITY, 4 * self.rttvar);
+        // a configured lower bound above RTO_MAX wins, rather than panicking in `clamp`
+        self.rto = std::cmp::max(std::cmp::min(rto, RTO_MAX), min_rto);
+        self.backoff = 0;
+    }
 }
 
 impl Connection {
     pub(crate) fn is_rcv_closed(&self) -> bool {
-        if let State::TimeWait = self.state {
-            true
-        } else {
-            false
-        }
+        // the peer has sent its FIN in all of these
+        matches!(
+            self.state,
+            State::Closed | State::Closing | State::TimeWait | State::CloseWait | State::LastAck
+        )
     }
 
     fn availability(&self) -> Available {
         let mut a = Available::empty();
+        if self.error.is_some() {
+            return Available::READ | Available::WRITE;
+        }
         if self.is_rcv_closed() || !self.incoming.is_empty() {
             a |= Available::READ;
         }
         // TODO: set available WRITE
         a
     }
+
+    /// Whether the connection can be removed from the connection table.
+    pub(crate) fn is_reapable(&self) -> bool {
+        self.state == State::Closed && self.orphaned
+    }
 }
 
 /// Send Sequence Space (RFC 793 S3.2 F4)
 ///
-/// ```
+/// ```text
 /// 1         2          3          4
 /// ----------|----------|----------|----------
 ///   SND.UNA    SND.NXT    SND.UNA
@@ -95,8 +356,11 @@ struct SendSequenceSpace {
     /// send next
     nxt: u32,
     /// send window
-    wnd: u16,
+    wnd: u32,
+    /// how far to shift the windows the peer advertises (Snd.Wind.Shift in RFC 7323)
+    wscale: u8,
     /// send urgent pointer
+    #[allow(dead_code)]
     up: bool,
     /// segment sequence number used for last window update
     wl1: u32,
@@ -108,7 +372,7 @@ struct SendSequenceSpace {
 
 /// Receive Sequence Space (RFC 793 S3.2 F5)
 ///
-/// ```
+/// ```text
 /// 1          2          3
 /// ----------|----------|----------
 ///    RCV.NXT    RCV.NXT
@@ -122,82 +386,217 @@ struct ReceiveSequenceSpace {
     /// receive next
     nxt: u32,
     /// receive window
-    wnd: u16,
+    wnd: u32,
+    /// how far we shift the windows we advertise (Rcv.Wind.Shift in RFC 7323)
+    wscale: u8,
     /// receive urgent pointer
+    #[allow(dead_code)]
     up: bool,
     /// initial receive sequence number
     irs: u32,
 }
 
 impl Connection {
-    pub fn accept(
-        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
-        ip_header: etherparse::Ipv4HeaderSlice,
-        tcp_header: etherparse::TcpHeaderSlice,
-        data: &[u8],
-    ) -> io::Result<Option<Self>> {
-        let mut buf = [0u8; 1500];
-        if !tcp_header.syn() {
-            // only expect SYN packet
-            return Ok(None);
-        }
-
-        let iss = 0;
-        let wnd = 1024;
-        let mut c = Self {
+    fn new(
+        config: &Config,
+        iss: u32,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        state: State,
+    ) -> Self {
+        let wnd = std::cmp::min(config.recv_buffer, u16::MAX as usize) as u16;
+        let wscale = window_shift(config.recv_buffer);
+        Self {
             closed: false,
             closed_at: None,
+            error: None,
+            orphaned: false,
+            passive: false,
+            aborting: false,
+            syn_acked: false,
+            sack_permitted: true,
+            window_scaling: true,
+            timestamps: Some(Timestamps {
+                base: time::Instant::now(),
+                recent: 0,
+                recent_at: time::Instant::now(),
+                last_ack_sent: 0,
+            }),
+            scoreboard: Scoreboard::default(),
+            rtx_next: iss,
+            mss: std::cmp::min(DEFAULT_MSS, config.mtu - HEADERS_LEN) as u32,
+            rcv_mss: DEFAULT_MSS as u32,
+            ack_pending: 0,
+            quickack: false,
+            nodelay: false,
+            keepalive: None,
             timers: Timers {
                 send_times: Default::default(),
-                srtt: time::Duration::from_secs(60).as_secs_f64(),
+                srtt: None,
+                rttvar: time::Duration::ZERO,
+                rto: std::cmp::max(RTO_INITIAL, config.min_rto),
+                backoff: 0,
+                retransmit: None,
+                time_wait: None,
+                fin_wait2: None,
+                persist: None,
+                persist_backoff: 0,
+                delayed_ack: None,
+                last_recv: time::Instant::now(),
+                keepalive_probes: 0,
             },
-            state: State::SynRcvd,
+            congestion: Congestion::new((config.congestion_control)(), DEFAULT_MSS as u32, iss),
+            state,
+            config: config.clone(),
             send: SendSequenceSpace {
-                iss: iss,
+                iss,
                 una: iss,
                 nxt: iss,
-                wnd: wnd,
+                wnd: 0,
+                wscale: 0,
                 up: false,
                 wl1: 0,
                 wl2: 0,
             },
             recv: ReceiveSequenceSpace {
-                irs: tcp_header.sequence_number(),
-                nxt: tcp_header.sequence_number() + 1,
-                wnd: tcp_header.window_size(),
+                irs: 0,
+                nxt: 0,
+                wnd: wnd as u32,
+                wscale,
                 up: false,
             },
             ip: etherparse::Ipv4Header::new(
                 0,
                 64,
                 etherparse::IpNumber::Tcp as u8,
-                [
-                    ip_header.destination()[0],
-                    ip_header.destination()[1],
-                    ip_header.destination()[2],
-                    ip_header.destination()[3],
-                ],
-                [
-                    ip_header.source()[0],
-                    ip_header.source()[1],
-                    ip_header.source()[2],
-                    ip_header.source()[3],
-                ],
-            ),
-            tcp: etherparse::TcpHeader::new(
-                tcp_header.destination_port(),
-                tcp_header.source_port(),
-                iss,
-                10,
+                local.0.octets(),
+                remote.0.octets(),
             ),
+            tcp: etherparse::TcpHeader::new(local.1, remote.1, iss, wnd),
             incoming: VecDeque::new(),
+            reassembly: Reassembly::default(),
             unacked: VecDeque::new(),
+        }
+    }
+
+    pub fn accept(
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+        config: &Config,
+        ip_header: etherparse::Ipv4HeaderSlice,
+        tcp_header: etherparse::TcpHeaderSlice,
+        _data: &[u8],
+    ) -> io::Result<Option<Self>> {
+        if !tcp_header.syn() {
+            // only expect SYN packet
+            return Ok(None);
+        }
+
+        let local = (ip_header.destination_addr(), tcp_header.destination_port());
+        let remote = (ip_header.source_addr(), tcp_header.source_port());
+        let iss = (config.isn)(local, remote);
+        Ok(Some(Self::answer_syn(
+            nic,
+            config,
+            iss,
+            &ip_header,
+            &tcp_header,
+        )?))
+    }
+
+    /// Answer a SYN to a listener whose SYN backlog is full with a SYN,ACK that carries a SYN
+    /// cookie, without keeping any state for it.
+    ///
+    /// If the peer completes the handshake, `from_cookie` rebuilds the connection from its ACK.
+    pub(crate) fn send_syn_cookie(
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+        config: &Config,
+        cookies: &SynCookies,
+        ip_header: &etherparse::Ipv4HeaderSlice,
+        tcp_header: &etherparse::TcpHeaderSlice,
+    ) -> io::Result<()> {
+        let local = (ip_header.destination_addr(), tcp_header.destination_port());
+        let remote = (ip_header.source_addr(), tcp_header.source_port());
+        let options = Options::parse(tcp_header);
+        // a cookie can't describe the peer's MSS, so drop the SYN as if the backlog had no room
+        let Some(iss) = cookies.make(local, remote, tcp_header.sequence_number(), &options) else {
+            return Ok(());
         };
+        Self::answer_syn(nic, config, iss, ip_header, tcp_header)?;
+        Ok(())
+    }
+
+    /// Rebuild a connection we answered with a SYN cookie from the ACK that completes its
+    /// handshake, if the ACK echoes a valid cookie.
+    ///
+    /// The connection is left in SYN-RECEIVED as if we had kept it all along; the ACK still has to
+    /// go through `on_packet`.
+    pub(crate) fn from_cookie(
+        config: &Config,
+        cookies: &SynCookies,
+        ip_header: &etherparse::Ipv4HeaderSlice,
+        tcp_header: &etherparse::TcpHeaderSlice,
+    ) -> Option<Self> {
+        let local = (ip_header.destination_addr(), tcp_header.destination_port());
+        let remote = (ip_header.source_addr(), tcp_header.source_port());
+        let irs = tcp_header.sequence_number().wrapping_sub(1);
+        let iss = tcp_header.acknowledgment_number().wrapping_sub(1);
+        let mut options = cookies.check(local, remote, irs, iss)?;
+        // the peer only sends timestamps if both SYNs had them
+        options.timestamp = Options::parse(tcp_header).timestamp;
+
+        let mut c = Self::new(config, iss, local, remote, State::SynRcvd);
+        c.passive = true;
+        c.recv.irs = irs;
+        c.recv.nxt = irs.wrapping_add(1);
+        c.on_syn_options(&options);
+        c.send.nxt = iss.wrapping_add(1);
+        c.send.wl1 = irs;
+        c.tcp.ack = true;
+        if let Some(ts) = &mut c.timestamps {
+            ts.last_ack_sent = c.recv.nxt;
+        }
+        Some(c)
+    }
+
+    /// Set up a passive open with `iss` for a SYN and send our SYN,ACK.
+    fn answer_syn(
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+        config: &Config,
+        iss: u32,
+        ip_header: &etherparse::Ipv4HeaderSlice,
+        tcp_header: &etherparse::TcpHeaderSlice,
+    ) -> io::Result<Self> {
+        let mut c = Self::new(
+            config,
+            iss,
+            (ip_header.destination_addr(), tcp_header.destination_port()),
+            (ip_header.source_addr(), tcp_header.source_port()),
+            State::SynRcvd,
+        );
+        c.passive = true;
+        c.recv.irs = tcp_header.sequence_number();
+        c.recv.nxt = tcp_header.sequence_number().wrapping_add(1);
+        c.on_syn_options(&Options::parse(tcp_header));
+        c.send.wnd = c.peer_window(tcp_header);
+        c.send.wl1 = tcp_header.sequence_number();
 
         c.tcp.syn = true;
         c.tcp.ack = true;
         c.write(nic, c.send.nxt, 0)?;
-        Ok(Some(c))
+        Ok(c)
+    }
+
+    /// Start an active open towards `remote`.
+    ///
+    /// The SYN is not sent here since only the packet loop has access to the device; it goes out
+    /// on the next call to `on_tick`.
+    pub(crate) fn connect(
+        config: &Config,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+    ) -> Self {
+        let iss = (config.isn)(local, remote);
+        Self::new(config, iss, local, remote, State::SynSent)
     }
 
     fn write(
@@ -206,18 +605,41 @@ impl Connection {
         seq: u32,
         mut limit: usize,
     ) -> io::Result<usize> {
-        let mut buf = [0u8; 1500];
+        let mut buf = vec![0u8; self.config.mtu];
         self.tcp.sequence_number = seq;
         self.tcp.acknowledgment_number = self.recv.nxt;
+        if self.tcp.syn {
+            // the window in a SYN is never scaled (RFC 7323 S2.2)
+            self.recv.wnd = std::cmp::min(self.receive_window(), u16::MAX as u32);
+            self.tcp.window_size = self.recv.wnd as u16;
+        } else {
+            self.recv.wnd = self.receive_window();
+            self.tcp.window_size = (self.recv.wnd >> self.recv.wscale) as u16;
+        }
+        self.tcp
+            .set_options(&self.options())
+            .expect("options fit in the TCP header");
+        if let Some(ts) = &mut self.timestamps {
+            ts.last_ack_sent = self.recv.nxt;
+        }
+        // whatever we send acknowledges everything received so far
+        self.ack_pending = 0;
+        self.timers.delayed_ack = None;
 
-        // special case the virtual bytes SYN and FIN
-        let mut offset = seq.wrapping_sub(self.send.una) as usize;
+        // special case the virtual bytes SYN and FIN, and window probes from before SND.UNA
+        let data_start = self.data_start();
+        let mut offset = seq.wrapping_sub(data_start) as usize;
         if let Some(closed_at) = self.closed_at {
             if seq == closed_at.wrapping_add(1) {
                 offset = 0;
                 limit = 0;
             }
         }
+        if wrapping_lt(seq, data_start) {
+            offset = 0;
+            limit = 0;
+        }
+        offset = std::cmp::min(offset, self.unacked.len());
 
         let (mut h, mut t) = self.unacked.as_slices();
         if h.len() >= offset {
@@ -231,15 +653,18 @@ impl Connection {
         let max_data = std::cmp::min(limit, h.len() + t.len());
         let size = std::cmp::min(
             buf.len(),
-            self.tcp.header_len() as usize + self.ip.header_len() as usize + max_data,
+            self.tcp.header_len() as usize + self.ip.header_len() + max_data,
         );
         self.ip
-            .set_payload_len(size - self.ip.header_len() as usize);
+            .set_payload_len(size - self.ip.header_len())
+            .expect("payload too large");
 
         let buf_len = buf.len();
         let mut unwritten = &mut buf[..];
 
-        self.ip.write(&mut unwritten);
+        self.ip
+            .write(&mut unwritten)
+            .expect("failed to write ip header");
         let ip_header_ends_at = buf_len - unwritten.len();
 
         unwritten = &mut unwritten[self.tcp.header_len() as usize..];
@@ -277,28 +702,115 @@ impl Connection {
             self.tcp.fin = false;
         }
 
+        if next_seq != seq {
+            // only segments that occupy sequence space get acknowledged and may need resending
+            let now = time::Instant::now();
+            let retransmission = wrapping_lt(seq, self.send.nxt);
+            self.timers.send_times.insert(seq, (now, retransmission));
+            if self.timers.retransmit.is_none() {
+                self.timers.retransmit = Some(now + self.timers.rto());
+            }
+        }
         if wrapping_lt(self.send.nxt, next_seq) {
             self.send.nxt = next_seq;
         }
-        self.timers.send_times.insert(seq, time::Instant::now());
 
         // write packet flags and protocol first
         let mut new_buf = vec![0, 0, 0, 2];
         new_buf.extend_from_slice(&buf[..payload_ends_at]);
 
-        nic.write(&new_buf)?;
+        nic.write_all(&new_buf)?;
         Ok(payload_bytes)
     }
 
+    /// The options to send on the segment `write` is about to build.
+    fn options(&self) -> Vec<TcpOptionElement> {
+        let mut options = Vec::new();
+        if self.tcp.syn {
+            let mss = self.config.mtu - HEADERS_LEN;
+            options.push(TcpOptionElement::MaximumSegmentSize(mss as u16));
+            if self.sack_permitted {
+                options.push(TcpOptionElement::SelectiveAcknowledgementPermitted);
+            }
+            if self.window_scaling {
+                options.push(TcpOptionElement::Noop);
+                options.push(TcpOptionElement::WindowScale(self.recv.wscale));
+            }
+        }
+
+        // the NOPs align the options that follow to four bytes, like everyone else does
+        let mut room = 40;
+        if let Some(ts) = &self.timestamps {
+            // there is nothing to echo before we have seen the peer's SYN
+            let echo = if self.tcp.ack { ts.recent } else { 0 };
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::Noop);
+            options.push(TcpOptionElement::Timestamp(ts.now(), echo));
+            room -= 12;
+        }
+
+        if !self.tcp.syn && self.sack_permitted && self.tcp.ack {
+            if let Some(sack) = options::sack(&self.reassembly.blocks(), room - 2) {
+                options.push(TcpOptionElement::Noop);
+                options.push(TcpOptionElement::Noop);
+                options.push(sack);
+            }
+        }
+        options
+    }
+
+    /// How much data fits in a segment next to the options we currently send.
+    fn segment_size(&self) -> u32 {
+        self.mss
+            .saturating_sub(options::len(&self.options()) as u32)
+    }
+
+    /// Take on what the peer's SYN says it supports.
+    fn on_syn_options(&mut self, options: &Options) {
+        let mss = options.mss.map_or(DEFAULT_MSS, |mss| mss as usize);
+        // a tiny MSS would leave no room for data next to our options
+        let mss = std::cmp::min(mss, self.config.mtu - HEADERS_LEN);
+        self.mss = std::cmp::max(mss, MIN_MSS) as u32;
+        self.congestion.set_mss(self.mss);
+        self.sack_permitted = options.sack_permitted;
+        match (&mut self.timestamps, options.timestamp) {
+            (Some(ts), Some((val, _))) => {
+                ts.recent = val;
+                ts.recent_at = time::Instant::now();
+            }
+            _ => self.timestamps = None,
+        }
+        match options.window_scale {
+            Some(shift) => self.send.wscale = std::cmp::min(shift, MAX_WINDOW_SHIFT),
+            None => {
+                // scaling only works if both sides do it
+                self.window_scaling = false;
+                self.recv.wscale = 0;
+            }
+        }
+    }
+
+    /// The window the peer advertises in `tcp_header`, in bytes.
+    fn peer_window(&self, tcp_header: &etherparse::TcpHeaderSlice) -> u32 {
+        if tcp_header.syn() {
+            tcp_header.window_size() as u32
+        } else {
+            (tcp_header.window_size() as u32) << self.send.wscale
+        }
+    }
+
+    /// Reset the connection from our side, using the current send sequence number.
     fn send_rst(
         &mut self,
         nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
     ) -> io::Result<()> {
-        self.tcp.rst = true;
-        self.tcp.sequence_number = 0;
-        self.tcp.acknowledgment_number = 0;
-        self.write(nic, self.send.nxt, 0)?;
-        Ok(())
+        write_rst(
+            nic,
+            self.ip.clone(),
+            (self.tcp.source_port, self.tcp.destination_port),
+            self.send.nxt,
+            None,
+        )
     }
 
     pub fn on_packet(
@@ -308,6 +820,30 @@ impl Connection {
         tcp_header: etherparse::TcpHeaderSlice,
         data: &[u8],
     ) -> io::Result<Available> {
+        match self.state {
+            State::Closed => {
+                // the connection is gone, only its entry has not been reaped yet
+                if !tcp_header.rst() {
+                    send_rst(nic, &ip_header, &tcp_header, data.len())?;
+                }
+                return Ok(self.availability());
+            }
+            State::SynSent => return self.on_syn_sent(nic, ip_header, tcp_header),
+            _ => {}
+        }
+
+        let options = Options::parse(&tcp_header);
+        if let (Some(ts), Some((val, _))) = (&self.timestamps, options.timestamp) {
+            if !tcp_header.rst()
+                && wrapping_lt(val, ts.recent)
+                && ts.recent_at.elapsed() < PAWS_IDLE
+            {
+                // an old duplicate from before the sequence numbers wrapped (RFC 7323 S5.3)
+                self.write(nic, self.send.nxt, 0)?;
+                return Ok(self.availability());
+            }
+        }
+
         // valid segment check
         // RCV.NXT <= SEG.SEQ < RCV.NXT + RCV.WND
         let seqn = tcp_header.sequence_number();
@@ -318,41 +854,61 @@ impl Connection {
         if tcp_header.syn() {
             slen += 1;
         }
-        let wend = self.recv.nxt.wrapping_add(self.recv.wnd as u32);
+        let wend = self.recv.nxt.wrapping_add(self.recv.wnd);
         let okay = if slen == 0 {
             // zero length segment
             if self.recv.wnd == 0 {
-                if seqn != self.recv.nxt {
-                    false
-                } else {
-                    true
-                }
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend) {
-                false
+                seqn == self.recv.nxt
             } else {
-                true
+                is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
             }
+        } else if self.recv.wnd == 0 {
+            false
         } else {
-            if self.recv.wnd == 0 {
-                false
-            } else if !is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
-                && !is_between_wrapped(
+            is_between_wrapped(self.recv.nxt.wrapping_sub(1), seqn, wend)
+                || is_between_wrapped(
                     self.recv.nxt.wrapping_sub(1),
                     seqn.wrapping_add(slen - 1),
                     wend,
                 )
-            {
-                false
-            } else {
-                true
-            }
         };
 
         if !okay {
+            if tcp_header.rst() {
+                // never answer a RST
+                return Ok(self.availability());
+            }
+            if self.state == State::TimeWait && tcp_header.fin() {
+                // the peer did not see our ACK of its FIN, restart the 2 MSL timeout
+                self.timers.time_wait = Some(time::Instant::now());
+            }
             self.write(nic, self.send.nxt, 0)?;
             return Ok(self.availability());
         }
 
+        self.timers.last_recv = time::Instant::now();
+        self.timers.keepalive_probes = 0;
+
+        if let (Some(ts), Some((val, _))) = (&mut self.timestamps, options.timestamp) {
+            // only take timestamps from segments we haven't acknowledged yet, so that a delayed
+            // ACK echoes the oldest one it covers (RFC 7323 S4.3)
+            if !wrapping_lt(val, ts.recent) && !wrapping_lt(ts.last_ack_sent, seqn) {
+                ts.recent = val;
+                ts.recent_at = time::Instant::now();
+            }
+        }
+
+        if tcp_header.rst() {
+            if seqn == self.recv.nxt {
+                self.reset();
+            } else {
+                // in the window but not exactly where we expect it, send a challenge ACK so that
+                // a genuine peer resends it with the right sequence number (RFC 5961 S3.2)
+                self.write(nic, self.send.nxt, 0)?;
+            }
+            return Ok(self.availability());
+        }
+
         if !tcp_header.ack() {
             if tcp_header.syn() {
                 self.recv.nxt = seqn.wrapping_add(1);
@@ -368,152 +924,1193 @@ impl Connection {
                 self.send.nxt.wrapping_add(1),
             ) {
                 self.state = State::Estab;
+                self.send.wnd = self.peer_window(&tcp_header);
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
             } else {
-                // TODO: reset
+                send_rst(nic, &ip_header, &tcp_header, data.len())?;
+                return Ok(self.availability());
             }
         }
 
-        if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
+        if let State::Estab
+        | State::FinWait1
+        | State::FinWait2
+        | State::CloseWait
+        | State::Closing
+        | State::LastAck = self.state
+        {
             if is_between_wrapped(self.send.una, ackn, self.send.nxt.wrapping_add(1)) {
+                let acked = ackn.wrapping_sub(self.send.una);
                 if !self.unacked.is_empty() {
-                    let data_start = if self.send.una == self.send.iss {
-                        self.send.una.wrapping_add(1)
-                    } else {
-                        self.send.una
-                    };
-                    let acked_data_end =
-                        std::cmp::min(ackn.wrapping_sub(data_start) as usize, self.unacked.len());
+                    let acked_data_end = std::cmp::min(
+                        ackn.wrapping_sub(self.data_start()) as usize,
+                        self.unacked.len(),
+                    );
                     self.unacked.drain(..acked_data_end);
-
-                    self.timers.send_times.retain(|&seq, sent| {
-                        if is_between_wrapped(self.send.una, seq, ackn) {
-                            let rtt = sent.elapsed();
-                            self.timers.srtt =
-                                0.8 * self.timers.srtt + (1.0 - 0.8) * rtt.as_secs_f64();
-                            false
-                        } else {
-                            true
-                        }
-                    });
                 }
+                // the SYN comes before anything else, so any progress acknowledges it
+                self.syn_acked |= acked > 0;
                 self.send.una = ackn;
+                self.on_ack_timers(ackn, &options);
+                self.scoreboard.advance(ackn);
+                self.update_scoreboard(&options);
+
+                if !self.congestion.on_ack(ackn, acked, self.timers.srtt) {
+                    // recovered, or nothing was lost
+                } else if self.congestion.after_timeout() {
+                    self.resend_after_timeout(nic)?;
+                } else {
+                    self.retransmit_lost(nic)?;
+                }
+            } else if ackn == self.send.una
+                && self.send.una != self.send.nxt
+                && data.is_empty()
+                && !tcp_header.syn()
+                && !tcp_header.fin()
+                && self.peer_window(&tcp_header) == self.send.wnd
+            {
+                self.update_scoreboard(&options);
+                let flight = self.send.nxt.wrapping_sub(self.send.una);
+                if self.congestion.on_dupack(ackn, flight, self.send.nxt) {
+                    self.rtx_next = self.send.una;
+                    self.retransmit_lost(nic)?;
+                } else if self.congestion.in_recovery() && self.sack_permitted {
+                    // each duplicate ACK in recovery lets us fill one more hole
+                    self.retransmit_hole(nic)?;
+                }
             }
 
-            // TODO: only read what we haven't read yet
-            // TODO: wake up awaiting readers
+            // take the peer's window from the most recent segment only (RFC 9293 S3.10.7.4)
+            if !wrapping_lt(ackn, self.send.una)
+                && !wrapping_lt(self.send.nxt, ackn)
+                && (wrapping_lt(self.send.wl1, seqn)
+                    || (self.send.wl1 == seqn && !wrapping_lt(ackn, self.send.wl2)))
+            {
+                self.send.wnd = self.peer_window(&tcp_header);
+                self.send.wl1 = seqn;
+                self.send.wl2 = ackn;
+            }
         }
 
-        if let State::FinWait1 = self.state {
-            if let Some(closed_at) = self.closed_at {
-                if self.send.una == closed_at.wrapping_add(1) {
-                    self.state = State::FinWait2;
+        if let Some(closed_at) = self.closed_at {
+            if self.send.una == closed_at.wrapping_add(1) {
+                // our FIN has been acknowledged
+                match self.state {
+                    State::FinWait1 => {
+                        self.state = State::FinWait2;
+                        self.timers.fin_wait2 = Some(time::Instant::now());
+                    }
+                    State::Closing => {
+                        self.state = State::TimeWait;
+                        self.timers.time_wait = Some(time::Instant::now());
+                    }
+                    State::LastAck => self.state = State::Closed,
+                    _ => {}
                 }
             }
         }
 
         if !data.is_empty() {
             if let State::Estab | State::FinWait1 | State::FinWait2 = self.state {
-                let mut unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
-                if unread_data_at > data.len() {
-                    // must have received retransmitted FIN
-                    unread_data_at = 0;
-                }
+                // never take in more than the window we advertised
+                let room = wend.wrapping_sub(seqn) as usize;
+                let data = &data[..std::cmp::min(data.len(), room)];
 
-                // only read what we haven't read yet
-                self.incoming.extend(&data[unread_data_at..]);
+                // out-of-order data is acknowledged right away so that the sender learns where the
+                // hole is, and so is data that fills one or that we had already (RFC 5681 S4.2)
+                let in_order = seqn == self.recv.nxt && self.reassembly.is_empty();
 
-                self.recv.nxt = seqn.wrapping_add(data.len() as u32);
+                if wrapping_lt(self.recv.nxt, seqn) {
+                    // there is a hole in front of this segment, hold on to it until it is filled
+                    self.reassembly.insert(self.recv.nxt, seqn, data);
+                } else {
+                    // only read what we haven't read yet
+                    let unread_data_at = self.recv.nxt.wrapping_sub(seqn) as usize;
+                    if unread_data_at < data.len() {
+                        self.deliver(&data[unread_data_at..]);
+                    }
+                }
+                while let Some(run) = self.reassembly.pop(self.recv.nxt) {
+                    self.deliver(&run);
+                }
 
-                self.write(nic, self.send.nxt, 0)?;
+                // ACK every second full segment, and otherwise within the ACK delay
+                self.rcv_mss = std::cmp::max(self.rcv_mss, data.len() as u32);
+                self.ack_pending += data.len() as u32;
+                if !in_order
+                    || tcp_header.psh()
+                    || self.quickack
+                    || self.ack_pending >= 2 * self.rcv_mss
+                {
+                    self.write(nic, self.send.nxt, 0)?;
+                } else if self.timers.delayed_ack.is_none() {
+                    let delay = std::cmp::min(self.config.ack_delay, MAX_ACK_DELAY);
+                    self.timers.delayed_ack = Some(time::Instant::now() + delay);
+                }
             }
         }
 
         if tcp_header.fin() {
-            match self.state {
-                State::FinWait2 => {
-                    // done with connection
-                    self.recv.nxt = self.recv.nxt.wrapping_add(1);
-                    self.write(nic, self.send.nxt, 0)?;
-                    self.state = State::TimeWait;
-                }
-                _ => {
-                    // unimplemented!(),
+            let fin_seq = seqn.wrapping_add(data.len() as u32);
+            if fin_seq.wrapping_sub(self.recv.nxt) <= self.recv.wnd {
+                self.reassembly.fin = Some(fin_seq);
+            }
+        }
+
+        // only act on a FIN once everything before it has been received
+        if self.reassembly.fin == Some(self.recv.nxt) {
+            let next = match self.state {
+                State::Estab => Some(State::CloseWait),
+                State::FinWait1 => Some(State::Closing),
+                // done with connection
+                State::FinWait2 => Some(State::TimeWait),
+                _ => None,
+            };
+            if let Some(next) = next {
+                self.recv.nxt = self.recv.nxt.wrapping_add(1);
+                self.write(nic, self.send.nxt, 0)?;
+                self.state = next;
+                if next == State::TimeWait {
+                    self.timers.time_wait = Some(time::Instant::now());
                 }
             }
         }
 
+        // the ACK may have opened up room in the window for more of what is queued
+        if let State::Estab | State::FinWait1 | State::CloseWait | State::Closing | State::LastAck =
+            self.state
+        {
+            self.send_data(nic)?;
+        }
+
         Ok(self.availability())
     }
 
-    pub(crate) fn on_tick(
+    /// Hand in-order data to the application and move RCV.NXT past it.
+    fn deliver(&mut self, data: &[u8]) {
+        // nobody is left to read data that arrives after the stream was dropped
+        if !self.orphaned {
+            self.incoming.extend(data);
+        }
+        self.recv.nxt = self.recv.nxt.wrapping_add(data.len() as u32);
+        // keep the right edge of the window where we advertised it
+        self.recv.wnd = self.recv.wnd.saturating_sub(data.len() as u32);
+    }
+
+    /// The window to advertise, based on how much room is left in the receive buffer.
+    ///
+    /// To avoid silly window syndrome (RFC 1122 S4.2.3.3) the window only opens up once it can
+    /// grow by a reasonable amount, and it never shrinks. It is kept to a multiple of the window
+    /// scale so that what we advertise is exactly what we accept.
+    fn receive_window(&self) -> u32 {
+        let free = self.config.recv_buffer.saturating_sub(self.incoming.len());
+        let free = std::cmp::min(free, (u16::MAX as usize) << self.recv.wscale);
+        let free = (free >> self.recv.wscale << self.recv.wscale) as u32;
+        let threshold = std::cmp::min(self.config.recv_buffer as u32 / 2, self.mss);
+        if free >= self.recv.wnd + threshold {
+            free
+        } else {
+            self.recv.wnd
+        }
+    }
+
+    /// Whether the application has read enough that the peer should hear about the bigger window.
+    pub(crate) fn window_update_due(&self) -> bool {
+        self.state.is_synchronized() && self.receive_window() != self.recv.wnd
+    }
+
+    /// Segment processing for an active open that has sent its SYN (RFC 9293 S3.10.7.3).
+    fn on_syn_sent(
         &mut self,
-        dev: &mut dyn tun::Device<Queue = tun::platform::Queue>,
-    ) -> io::Result<()> {
-        if let State::FinWait2 | State::TimeWait = self.state {
-            return Ok(());
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+        ip_header: etherparse::Ipv4HeaderSlice,
+        tcp_header: etherparse::TcpHeaderSlice,
+    ) -> io::Result<Available> {
+        let ackn = tcp_header.acknowledgment_number();
+        // ISS < SEG.ACK <= SND.NXT
+        let ack_okay = tcp_header.ack()
+            && is_between_wrapped(self.send.iss, ackn, self.send.nxt.wrapping_add(1));
+        if tcp_header.ack() && !ack_okay {
+            if !tcp_header.rst() {
+                send_rst(nic, &ip_header, &tcp_header, 0)?;
+            }
+            return Ok(self.availability());
         }
 
-        let nunacked_data = self
-            .closed_at
-            .unwrap_or(self.send.nxt)
-            .wrapping_sub(self.send.una);
-        let nunsent_data = self.unacked.len() as u32 - nunacked_data;
+        if tcp_header.rst() {
+            if ack_okay {
+                self.state = State::Closed;
+                self.error = Some(io::ErrorKind::ConnectionRefused);
+            }
+            return Ok(self.availability());
+        }
 
-        let waited_for = self
-            .timers
-            .send_times
-            .range(self.send.una..)
-            .next()
-            .map(|(_, t)| t.elapsed());
+        if !tcp_header.syn() {
+            return Ok(self.availability());
+        }
+
+        let seqn = tcp_header.sequence_number();
+        self.recv.irs = seqn;
+        self.recv.nxt = seqn.wrapping_add(1);
+        let options = Options::parse(&tcp_header);
+        self.on_syn_options(&options);
+        self.send.wnd = self.peer_window(&tcp_header);
+        self.send.wl1 = seqn;
+        self.send.wl2 = ackn;
+        self.tcp.ack = true;
 
-        let should_retransmit = if let Some(waited_for) = waited_for {
-            waited_for > time::Duration::from_secs(1)
-                && waited_for.as_secs_f64() > 1.5 * self.timers.srtt
+        if ack_okay {
+            self.send.una = ackn;
+            self.syn_acked = true;
+            self.on_ack_timers(ackn, &options);
+            self.state = State::Estab;
+            self.write(nic, self.send.nxt, 0)?;
         } else {
-            false
+            // simultaneous open, answer with SYN,ACK
+            self.state = State::SynRcvd;
+            self.tcp.syn = true;
+            self.write(nic, self.send.iss, 0)?;
+        }
+
+        Ok(self.availability())
+    }
+
+    /// Measure the round-trip time from newly acknowledged segments and restart the retransmission
+    /// timer for whatever is still in flight (RFC 6298 S5.2, S5.3).
+    ///
+    /// With timestamps the echo in the ACK tells us which transmission it answers, so we can
+    /// measure even when it acknowledges a retransmission (RFC 7323 S4.2).
+    fn on_ack_timers(&mut self, ackn: u32, options: &Options) {
+        let mut newest = None;
+        let mut ambiguous = false;
+        self.timers
+            .send_times
+            .retain(|&seq, &mut (sent, retransmission)| {
+                if wrapping_lt(seq, ackn) {
+                    newest = std::cmp::max(newest, Some(sent));
+                    ambiguous |= retransmission;
+                    false
+                } else {
+                    true
+                }
+            });
+
+        let echoed = match (&self.timestamps, options.timestamp) {
+            (Some(ts), Some((_, echo))) if echo != 0 => Some(time::Duration::from_millis(
+                ts.now().wrapping_sub(echo) as u64,
+            )),
+            _ => None,
         };
 
-        if should_retransmit {
-            // should retransmit
-            let resend = std::cmp::min(self.unacked.len() as u32, self.send.wnd as u32);
-            if resend < self.send.wnd as u32 && self.closed {
-                // should we resend FIN
-                self.tcp.fin = true;
-                self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+        if let Some(sent) = newest {
+            if let Some(rtt) = echoed {
+                self.timers.sample(rtt, self.config.min_rto);
+            } else if !ambiguous {
+                self.timers.sample(sent.elapsed(), self.config.min_rto);
+            } else if self.timers.srtt.is_none() {
+                // we can't tell which transmission this ACK is for (Karn's algorithm), but losing
+                // the very first segments suggests the initial RTO is too short for this path
+                self.timers.rto = std::cmp::max(self.timers.rto, RTO_AFTER_SYN_LOSS);
+                self.timers.backoff = 0;
             }
-            self.write(dev, self.send.una, resend as usize)?;
+        }
+
+        self.timers.retransmit = if self.send.una == self.send.nxt {
+            None
         } else {
-            // send new data if new data available and space in window
-            if nunsent_data == 0 && self.closed_at.is_some() {
-                return Ok(());
+            Some(time::Instant::now() + self.timers.rto())
+        };
+    }
+
+    /// The current retransmission timeout, including any backoff.
+    pub(crate) fn rto(&self) -> time::Duration {
+        self.timers.rto()
+    }
+
+    /// Switch this connection to another congestion control algorithm.
+    pub(crate) fn set_congestion_control(&mut self, controller: Box<dyn CongestionControl>) {
+        self.congestion.set_controller(controller);
+    }
+
+    /// Back off after the retransmission timer expired (RFC 6298 S5.5, S5.6).
+    ///
+    /// Call this after resending, since the timer is restarted from here even if nothing that
+    /// occupies sequence space could be sent.
+    fn on_retransmit_timeout(&mut self) {
+        self.timers.backoff = self.timers.backoff.saturating_add(1);
+        self.timers.retransmit = Some(time::Instant::now() + self.timers.rto());
+    }
+
+    /// Tear the connection down after the peer reset it (RFC 9293 S3.10.7.4).
+    fn reset(&mut self) {
+        self.error = match self.state {
+            // a passive open goes back to listening, nobody has seen this connection yet
+            State::SynRcvd if self.passive => {
+                self.orphaned = true;
+                None
+            }
+            State::SynRcvd => Some(io::ErrorKind::ConnectionRefused),
+            State::Estab | State::FinWait1 | State::FinWait2 | State::CloseWait => {
+                Some(io::ErrorKind::ConnectionReset)
+            }
+            _ => None,
+        };
+        self.state = State::Closed;
+        self.incoming.clear();
+        self.unacked.clear();
+        self.timers.send_times.clear();
+        self.timers.retransmit = None;
+    }
+
+    pub(crate) fn on_tick(
+        &mut self,
+        dev: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ) -> io::Result<()> {
+        if self.aborting {
+            self.aborting = false;
+            // RFC 9293 S3.10.5: only tell the peer if it may think the connection is still open,
+            // and only fail the user's calls if the connection had not finished yet
+            match self.state {
+                State::SynRcvd
+                | State::Estab
+                | State::FinWait1
+                | State::FinWait2
+                | State::CloseWait => {
+                    self.send_rst(dev)?;
+                    self.error.get_or_insert(io::ErrorKind::ConnectionAborted);
+                }
+                State::SynSent => {
+                    self.error.get_or_insert(io::ErrorKind::ConnectionAborted);
+                }
+                State::Closing | State::LastAck | State::TimeWait | State::Closed => {}
+            }
+            self.state = State::Closed;
+            self.unacked.clear();
+            self.timers.send_times.clear();
+            self.timers.retransmit = None;
+            if self.error.is_some() {
+                self.incoming.clear();
             }
+            return Ok(());
+        }
 
-            let allowed = self.send.wnd as u32 - nunacked_data;
-            if allowed == 0 {
+        match self.state {
+            State::SynSent | State::SynRcvd => {
+                // until the handshake completes, our SYN is the only thing we can have in flight
+                if self.send.nxt == self.send.iss {
+                    self.tcp.syn = true;
+                    self.write(dev, self.send.iss, 0)?;
+                } else if self.should_retransmit() {
+                    if self.passive && self.timers.backoff >= SYNACK_RETRIES {
+                        // the peer is gone, so stop holding a place in the SYN backlog for it;
+                        // as with a reset, nobody has seen this connection yet
+                        self.state = State::Closed;
+                        self.orphaned = true;
+                        self.timers.retransmit = None;
+                        return Ok(());
+                    }
+                    self.congestion.on_timeout(1, self.send.nxt);
+                    self.tcp.syn = true;
+                    self.write(dev, self.send.iss, 0)?;
+                    self.on_retransmit_timeout();
+                }
+                return Ok(());
+            }
+            State::TimeWait => {
+                let expired = self
+                    .timers
+                    .time_wait
+                    .is_none_or(|t| t.elapsed() >= self.config.msl.saturating_mul(2));
+                if expired {
+                    self.state = State::Closed;
+                }
+                return Ok(());
+            }
+            State::FinWait2 => {
+                // with nobody left to read, don't wait forever for a peer that may never close
+                let expired = self
+                    .timers
+                    .fin_wait2
+                    .is_none_or(|t| t.elapsed() >= self.config.fin_timeout);
+                if self.orphaned && expired {
+                    self.state = State::Closed;
+                } else if self.window_update_due() || self.delayed_ack_due() {
+                    self.write(dev, self.send.nxt, 0)?;
+                }
                 return Ok(());
             }
+            State::Closed => return Ok(()),
+            _ => {}
+        }
+
+        if self.should_retransmit() && self.timers.backoff >= RETRIES {
+            // the peer is gone; there is no point in telling it with a RST
+            self.error.get_or_insert(io::ErrorKind::TimedOut);
+            self.state = State::Closed;
+            self.incoming.clear();
+            self.unacked.clear();
+            self.timers.send_times.clear();
+            self.timers.retransmit = None;
+        } else if self.should_retransmit() {
+            let flight = self.send.nxt.wrapping_sub(self.send.una);
+            self.congestion.on_timeout(flight, self.send.nxt);
+            self.scoreboard.clear();
+            let limit = std::cmp::min(self.send.wnd, self.congestion.window());
+            let resent = self.retransmit(dev, limit)?;
+            self.rtx_next = self.send.una.wrapping_add(resent);
+            self.on_retransmit_timeout();
+        } else {
+            self.send_data(dev)?;
+        }
+        // nothing went out that the ACK could ride along on
+        if self.delayed_ack_due() {
+            self.write(dev, self.send.nxt, 0)?;
+        }
+        if let State::Estab | State::CloseWait = self.state {
+            self.on_keepalive_tick(dev)?;
+        }
 
+        Ok(())
+    }
+
+    /// Probe a connection that has been idle for too long, and give up on it if the peer stopped
+    /// answering (RFC 1122 S4.2.3.6).
+    ///
+    /// A probe is an empty segment from just before SND.NXT, which the peer has to answer with an
+    /// ACK. Only idle connections are probed; with data in flight, the retransmission timer gives
+    /// up on a peer that stopped answering after `RETRIES` timeouts.
+    fn on_keepalive_tick(
+        &mut self,
+        dev: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ) -> io::Result<()> {
+        let Some(keepalive) = self.keepalive else {
+            return Ok(());
+        };
+        if self.send.una != self.send.nxt {
+            return Ok(());
+        }
+        let due = keepalive.idle.saturating_add(
+            keepalive
+                .interval
+                .saturating_mul(self.timers.keepalive_probes),
+        );
+        if self.timers.last_recv.elapsed() < due {
+            return Ok(());
+        }
+
+        if self.timers.keepalive_probes >= keepalive.count {
+            // in case the peer is still around but we can't hear it, tell it we are giving up
+            self.send_rst(dev)?;
+            self.error = Some(io::ErrorKind::TimedOut);
+            self.state = State::Closed;
+            self.incoming.clear();
+            self.unacked.clear();
+            return Ok(());
+        }
+        self.write(dev, self.send.nxt.wrapping_sub(1), 0)?;
+        self.timers.keepalive_probes += 1;
+        Ok(())
+    }
+
+    /// Whether the ACK we held back for received data has waited long enough.
+    fn delayed_ack_due(&self) -> bool {
+        self.timers
+            .delayed_ack
+            .is_some_and(|at| time::Instant::now() >= at)
+    }
+
+    /// Send as much queued data as the peer's and the congestion window allow, in segments of up
+    /// to the MSS, followed by our FIN once everything has been sent after `close`.
+    fn send_data(
+        &mut self,
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ) -> io::Result<()> {
+        loop {
+            let nunacked_data = self
+                .closed_at
+                .unwrap_or(self.send.nxt)
+                .wrapping_sub(self.send.una);
+            let nunsent_data = self.unacked.len() as u32 - nunacked_data;
+
+            let window = std::cmp::min(self.send.wnd, self.congestion.window());
+            let allowed = window.saturating_sub(nunacked_data);
             let send = std::cmp::min(nunsent_data, allowed);
-            if send < allowed && self.closed && self.closed_at.is_none() {
+            let mut send = std::cmp::min(send, self.segment_size());
+            // Nagle's algorithm: while data is in flight, hold on to less than a full segment
+            // until the ACK comes back or more is queued (RFC 1122 S4.2.3.4)
+            if !self.nodelay
+                && !self.closed
+                && nunacked_data > 0
+                && nunsent_data < self.segment_size()
+            {
+                send = 0;
+            }
+            // the FIN goes on the segment with the last of the data, if the window has room for it
+            if send == nunsent_data && send < allowed && self.closed && self.closed_at.is_none() {
                 self.tcp.fin = true;
                 self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
             }
 
-            self.write(dev, self.send.nxt, send as usize)?;
+            // an otherwise empty segment is still worth sending to open our window
+            let mut sent = 0;
+            if send > 0 || self.tcp.fin || self.window_update_due() {
+                sent = self.write(nic, self.send.nxt, send as usize)?;
+            }
+            // stop once nothing more goes out, whatever the reason
+            if sent > 0 {
+                continue;
+            }
+
+            let pending_fin = self.closed && self.closed_at.is_none();
+            if self.send.wnd == 0 && nunacked_data == 0 && (nunsent_data > 0 || pending_fin) {
+                self.on_persist_tick(nic)?;
+            } else {
+                self.timers.persist = None;
+                self.timers.persist_backoff = 0;
+            }
+            return Ok(());
         }
+    }
 
+    /// Probe a zero window so that a lost window update can't stall us forever (RFC 9293 S3.8.6.1).
+    ///
+    /// The probe is an empty segment from just before SND.UNA, which the peer has to answer with
+    /// an ACK carrying its current window. Probes start out one RTO apart and back off from there.
+    fn on_persist_tick(
+        &mut self,
+        dev: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ) -> io::Result<()> {
+        let now = time::Instant::now();
+        let timeout = std::cmp::min(
+            self.timers
+                .rto()
+                .saturating_mul(2u32.saturating_pow(self.timers.persist_backoff)),
+            RTO_MAX,
+        );
+        match self.timers.persist {
+            None => self.timers.persist = Some(now + timeout),
+            Some(at) if now >= at => {
+                self.write(dev, self.send.una.wrapping_sub(1), 0)?;
+                self.timers.persist_backoff += 1;
+                let timeout = std::cmp::min(timeout * 2, RTO_MAX);
+                self.timers.persist = Some(now + timeout);
+            }
+            Some(_) => {}
+        }
         Ok(())
     }
 
-    pub(crate) fn close(&mut self) {
+    /// Resend a segment of up to `limit` bytes from SND.UNA, along with our FIN if it fits.
+    ///
+    /// Returns how much data was resent.
+    fn retransmit(
+        &mut self,
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+        limit: u32,
+    ) -> io::Result<u32> {
+        let resend = std::cmp::min(self.unacked.len() as u32, limit);
+        let resend = std::cmp::min(resend, self.segment_size());
+        if resend == self.unacked.len() as u32 && resend < limit && self.closed {
+            self.tcp.fin = true;
+            self.closed_at = Some(self.send.una.wrapping_add(self.unacked.len() as u32));
+        }
+        self.write(nic, self.send.una, resend as usize)?;
+        Ok(resend)
+    }
+
+    /// Record the SACK blocks on an acceptable ACK.
+    fn update_scoreboard(&mut self, options: &Options) {
+        if !self.sack_permitted {
+            return;
+        }
+        for &(start, end) in &options.sack {
+            self.scoreboard
+                .insert(self.send.una, self.send.nxt, start, end);
+        }
+    }
+
+    /// Resend what the peer is missing after a loss.
+    ///
+    /// With SACK that is the next hole in what the peer holds, otherwise (or if the peer hasn't
+    /// told us about anything past it) the segment at SND.UNA, unless we already resent that.
+    fn retransmit_lost(
+        &mut self,
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ) -> io::Result<()> {
+        if self.sack_permitted && self.retransmit_hole(nic)? {
+            return Ok(());
+        }
+        if !wrapping_lt(self.send.una, self.rtx_next) {
+            let resent = self.retransmit(nic, self.mss)?;
+            self.rtx_next = self.send.una.wrapping_add(resent);
+        }
+        Ok(())
+    }
+
+    /// The sequence number of the first byte in `unacked`.
+    ///
+    /// While our SYN is unacknowledged, SND.UNA is the SYN itself and data starts one past it.
+    fn data_start(&self) -> u32 {
+        if self.syn_acked {
+            self.send.una
+        } else {
+            self.send.una.wrapping_add(1)
+        }
+    }
+
+    /// Resend what was in flight when the retransmission timer expired, from `rtx_next` on and as
+    /// far as the window allows (go-back-N, RFC 5681 S3.1).
+    ///
+    /// Whatever the peer had beyond SND.UNA may be gone, so we can't rely on it to tell us about
+    /// the next loss; without this, each round trip would only repair one segment.
+    fn resend_after_timeout(
+        &mut self,
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ) -> io::Result<()> {
+        if wrapping_lt(self.rtx_next, self.send.una) {
+            self.rtx_next = self.send.una;
+        }
+        let window = std::cmp::min(self.send.wnd, self.congestion.window());
+        let data_end = self.closed_at.unwrap_or(self.send.nxt);
+        while wrapping_lt(self.rtx_next, self.send.nxt) {
+            let room = window.saturating_sub(self.rtx_next.wrapping_sub(self.send.una));
+            let len = data_end.wrapping_sub(self.rtx_next);
+            let len = std::cmp::min(std::cmp::min(len, room), self.segment_size());
+            // the FIN goes along once the last of the data does
+            let fin = self.closed_at == Some(self.rtx_next.wrapping_add(len)) && len < room;
+            if len == 0 && !fin {
+                break;
+            }
+            self.tcp.fin = fin;
+            self.write(nic, self.rtx_next, len as usize)?;
+            self.rtx_next = self.rtx_next.wrapping_add(len + fin as u32);
+        }
+        Ok(())
+    }
+
+    /// Resend the start of the first hole at or after `rtx_next`, returning whether there was one.
+    fn retransmit_hole(
+        &mut self,
+        nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ) -> io::Result<bool> {
+        let Some((start, end)) = self.scoreboard.next_hole(self.send.una, self.rtx_next) else {
+            return Ok(false);
+        };
+        let len = std::cmp::min(end.wrapping_sub(start), self.segment_size());
+        self.write(nic, start, len as usize)?;
+        self.rtx_next = start.wrapping_add(len);
+        Ok(true)
+    }
+
+    fn should_retransmit(&self) -> bool {
+        self.timers
+            .retransmit
+            .is_some_and(|at| time::Instant::now() >= at)
+    }
+
+    /// Abort the connection; the RST is sent from `on_tick` since that is where we have the device.
+    pub(crate) fn abort(&mut self) {
+        if self.state != State::Closed {
+            self.aborting = true;
+        }
+    }
+
+    /// Close our sending side; the FIN goes out from `on_tick` once all queued data has been sent.
+    pub(crate) fn close(&mut self) -> io::Result<()> {
+        match self.state {
+            State::SynRcvd | State::Estab => self.state = State::FinWait1,
+            State::CloseWait => self.state = State::LastAck,
+            s if s.have_sent_fin() => {}
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    "connection not established",
+                ));
+            }
+        }
         self.closed = true;
+        Ok(())
     }
 }
 
+/// Answer a segment that no connection will accept with a RST (RFC 9293 S3.10.7.1).
+///
+/// The RST takes its sequence number from the offending segment's ACK if it has one, and
+/// otherwise acknowledges everything in the segment so the sender accepts it.
+pub(crate) fn send_rst(
+    nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    ip_header: &etherparse::Ipv4HeaderSlice,
+    tcp_header: &etherparse::TcpHeaderSlice,
+    data_len: usize,
+) -> io::Result<()> {
+    let (seq, ack) = if tcp_header.ack() {
+        (tcp_header.acknowledgment_number(), None)
+    } else {
+        let mut slen = data_len as u32;
+        if tcp_header.syn() {
+            slen += 1;
+        }
+        if tcp_header.fin() {
+            slen += 1;
+        }
+        (0, Some(tcp_header.sequence_number().wrapping_add(slen)))
+    };
+    let ip = etherparse::Ipv4Header::new(
+        0,
+        64,
+        etherparse::IpNumber::Tcp as u8,
+        ip_header.destination_addr().octets(),
+        ip_header.source_addr().octets(),
+    );
+    write_rst(
+        nic,
+        ip,
+        (tcp_header.destination_port(), tcp_header.source_port()),
+        seq,
+        ack,
+    )
+}
+
+fn write_rst(
+    nic: &mut dyn tun::Device<Queue = tun::platform::Queue>,
+    mut ip: etherparse::Ipv4Header,
+    ports: (u16, u16),
+    seq: u32,
+    ack: Option<u32>,
+) -> io::Result<()> {
+    let mut tcp = etherparse::TcpHeader::new(ports.0, ports.1, seq, 0);
+    tcp.rst = true;
+    if let Some(ack) = ack {
+        tcp.ack = true;
+        tcp.acknowledgment_number = ack;
+    }
+    ip.set_payload_len(tcp.header_len() as usize)
+        .expect("payload too large");
+    tcp.checksum = tcp
+        .calc_checksum_ipv4(&ip, &[])
+        .expect("failed to checksum");
+
+    // write packet flags and protocol first
+    let mut buf = vec![0, 0, 0, 2];
+    ip.write(&mut buf).expect("failed to write ip header");
+    tcp.write(&mut buf)?;
+    nic.write_all(&buf)?;
+    Ok(())
+}
+
+/// The smallest window scale shift that lets us advertise all of a `buffer` byte receive buffer.
+fn window_shift(buffer: usize) -> u8 {
+    let mut shift = 0;
+    while shift < MAX_WINDOW_SHIFT && buffer >> shift > u16::MAX as usize {
+        shift += 1;
+    }
+    shift
+}
+
 fn wrapping_lt(lhs: u32, rhs: u32) -> bool {
-    lhs.wrapping_sub(rhs) > 2 ^ 31
+    lhs.wrapping_sub(rhs) > 1 << 31
 }
 
 fn is_between_wrapped(start: u32, x: u32, end: u32) -> bool {
     wrapping_lt(start, x) && wrapping_lt(x, end)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{self, IRS, ISS};
+
+    fn config() -> Config {
+        Config {
+            isn: Arc::new(|_, _| ISS),
+            ..Config::default()
+        }
+    }
+
+    /// A passive connection that has answered the SYN `syn` describes, and its SYN,ACK.
+    fn accept(
+        nic: &mut mock::Device,
+        config: &Config,
+        syn: etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+    ) -> (Connection, etherparse::TcpHeader) {
+        let syn = mock::packet(syn, &[]);
+        let (ip, tcp, data) = mock::headers(&syn);
+        let c = Connection::accept(nic, config, ip, tcp, data)
+            .unwrap()
+            .expect("SYN is accepted");
+        let mut sent = nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (syn_ack, _) = sent.pop().unwrap();
+        assert!(syn_ack.syn && syn_ack.ack);
+        (c, syn_ack)
+    }
+
+    /// A passive connection that has answered a SYN without options.
+    fn syn_rcvd(nic: &mut mock::Device) -> Connection {
+        accept(nic, &config(), mock::segment(IRS).syn()).0
+    }
+
+    /// A passive connection whose handshake without options is done.
+    fn established(nic: &mut mock::Device) -> Connection {
+        let mut c = syn_rcvd(nic);
+        deliver(&mut c, nic, mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        assert_eq!(c.state, State::Estab);
+        assert!(nic.take_segments().is_empty());
+        c
+    }
+
+    /// The options on a segment we sent.
+    fn sent_options(tcp: &etherparse::TcpHeader) -> Options {
+        let mut bytes = Vec::new();
+        tcp.write(&mut bytes).unwrap();
+        Options::parse(&etherparse::TcpHeaderSlice::from_slice(&bytes).unwrap())
+    }
+
+    /// Feed `c` the segment `builder` describes, carrying `payload`.
+    fn deliver(
+        c: &mut Connection,
+        nic: &mut mock::Device,
+        builder: etherparse::PacketBuilderStep<etherparse::TcpHeader>,
+        payload: &[u8],
+    ) {
+        let segment = mock::packet(builder, payload);
+        let (ip, tcp, data) = mock::headers(&segment);
+        c.on_packet(nic, ip, tcp, data).unwrap();
+    }
+
+    #[test]
+    fn challenge_ack_in_syn_rcvd() {
+        let mut nic = mock::Device::default();
+        let mut c = syn_rcvd(&mut nic);
+
+        // in the window, but not at RCV.NXT
+        deliver(&mut c, &mut nic, mock::segment(IRS + 10).rst(), &[]);
+
+        assert_eq!(c.state, State::SynRcvd);
+        let sent = nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (ack, data) = &sent[0];
+        assert!(ack.ack && !ack.syn && !ack.rst);
+        assert_eq!(ack.sequence_number, ISS + 1);
+        assert_eq!(ack.acknowledgment_number, IRS + 1);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn retransmitted_syn_in_syn_rcvd() {
+        let mut nic = mock::Device::default();
+        let mut c = syn_rcvd(&mut nic);
+
+        deliver(&mut c, &mut nic, mock::segment(IRS).syn(), &[]);
+
+        assert_eq!(c.state, State::SynRcvd);
+        let sent = nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        let (ack, data) = &sent[0];
+        assert!(ack.ack && !ack.rst);
+        assert_eq!(ack.acknowledgment_number, IRS + 1);
+        assert!(data.is_empty());
+    }
+
+    #[test]
+    fn syn_ack_retries_give_up() {
+        let mut nic = mock::Device::default();
+        let mut c = syn_rcvd(&mut nic);
+
+        for _ in 0..SYNACK_RETRIES {
+            c.timers.retransmit = Some(time::Instant::now());
+            c.on_tick(&mut nic).unwrap();
+            let sent = nic.take_segments();
+            assert_eq!(sent.len(), 1);
+            assert!(sent[0].0.syn && sent[0].0.ack);
+            assert_eq!(sent[0].0.sequence_number, ISS);
+        }
+        assert_eq!(c.state, State::SynRcvd);
+
+        c.timers.retransmit = Some(time::Instant::now());
+        c.on_tick(&mut nic).unwrap();
+        assert!(nic.take_segments().is_empty());
+        assert!(c.is_reapable());
+    }
+
+    #[test]
+    fn tiny_mss_is_raised() {
+        let mut nic = mock::Device::default();
+        let config = Config {
+            congestion_control: Arc::new(|| Box::new(Cubic::new())),
+            ..config()
+        };
+        let syn = mock::segment(IRS)
+            .syn()
+            .options(&[TcpOptionElement::MaximumSegmentSize(0)])
+            .unwrap();
+        let (c, _) = accept(&mut nic, &config, syn);
+        assert_eq!(c.mss, MIN_MSS as u32);
+        assert!(c.congestion.window() > 0);
+
+        // even with timestamps taking up room in every segment
+        let syn = mock::segment(IRS)
+            .syn()
+            .options(&[
+                TcpOptionElement::MaximumSegmentSize(8),
+                TcpOptionElement::Timestamp(1, 0),
+            ])
+            .unwrap();
+        let (c, _) = accept(&mut nic, &config, syn);
+        assert!(c.segment_size() > 0);
+    }
+
+    #[test]
+    fn min_rto_above_rto_max() {
+        let mut nic = mock::Device::default();
+        let config = Config {
+            min_rto: time::Duration::from_secs(120),
+            ..config()
+        };
+        let (mut c, _) = accept(&mut nic, &config, mock::segment(IRS).syn());
+        c.timers
+            .sample(time::Duration::from_millis(10), c.config.min_rto);
+        assert_eq!(c.rto(), RTO_MAX);
+    }
+
+    #[test]
+    fn retransmissions_give_up() {
+        let mut nic = mock::Device::default();
+        let mut c = established(&mut nic);
+        c.unacked.extend(b"hello");
+        c.on_tick(&mut nic).unwrap();
+        assert_eq!(nic.take_segments()[0].1, b"hello");
+
+        for _ in 0..RETRIES {
+            c.timers.retransmit = Some(time::Instant::now());
+            c.on_tick(&mut nic).unwrap();
+            let sent = nic.take_segments();
+            assert_eq!(sent.len(), 1);
+            assert_eq!(sent[0].1, b"hello");
+        }
+        assert_eq!(c.state, State::Estab);
+
+        c.timers.retransmit = Some(time::Instant::now());
+        c.on_tick(&mut nic).unwrap();
+        assert!(nic.take_segments().is_empty());
+        assert_eq!(c.state, State::Closed);
+        assert_eq!(c.error, Some(io::ErrorKind::TimedOut));
+    }
+
+    #[test]
+    fn go_back_n_after_timeout() {
+        let mut nic = mock::Device::default();
+        let mut c = established(&mut nic);
+        let mss = c.segment_size();
+        c.unacked.extend(vec![0; 8 * mss as usize]);
+        c.on_tick(&mut nic).unwrap();
+        let flight = nic.take_segments().len() as u32;
+        assert!(flight > 2);
+
+        c.timers.retransmit = Some(time::Instant::now());
+        c.on_tick(&mut nic).unwrap();
+        let sent = nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1);
+        assert_eq!(c.congestion.window(), mss);
+
+        // slow start lets two segments go for the one acknowledged, both from the old flight
+        let ack = mock::segment(IRS + 1).ack(ISS + 1 + mss);
+        deliver(&mut c, &mut nic, ack, &[]);
+        let sent = nic.take_segments();
+        assert_eq!(sent.len(), 2);
+        assert_eq!(sent[0].0.sequence_number, ISS + 1 + mss);
+        assert_eq!(sent[1].0.sequence_number, ISS + 1 + 2 * mss);
+        assert_eq!(sent[1].1.len(), mss as usize);
+
+        // and so on, until everything from before the timeout has been resent
+        let ack = mock::segment(IRS + 1).ack(ISS + 1 + 3 * mss);
+        deliver(&mut c, &mut nic, ack, &[]);
+        let sent = nic.take_segments();
+        let resent: Vec<_> = sent.iter().map(|(tcp, _)| tcp.sequence_number).collect();
+        let expected: Vec<_> = (3..flight).map(|i| ISS + 1 + i * mss).collect();
+        assert_eq!(resent[..expected.len()], expected[..]);
+    }
+
+    #[test]
+    fn data_after_sequence_numbers_wrap_to_iss() {
+        let mut nic = mock::Device::default();
+        let mut c = established(&mut nic);
+        // as if exactly 2^32 bytes had been sent and acknowledged since the SYN
+        c.send.una = ISS;
+        c.send.nxt = ISS;
+        c.unacked.extend(b"hello");
+        c.on_tick(&mut nic).unwrap();
+        let sent = nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.sequence_number, ISS);
+        assert_eq!(sent[0].1, b"hello");
+
+        let ack = mock::segment(IRS + 1).ack(ISS + 2);
+        deliver(&mut c, &mut nic, ack, &[]);
+        assert_eq!(c.unacked, b"llo");
+    }
+
+    #[test]
+    fn window_scaling_with_peer_option() {
+        let mut nic = mock::Device::default();
+        let config = Config {
+            recv_buffer: 1 << 20,
+            ..config()
+        };
+        let shift = window_shift(1 << 20);
+        let syn = mock::segment(IRS)
+            .syn()
+            .options(&[TcpOptionElement::WindowScale(2)])
+            .unwrap();
+        let (mut c, syn_ack) = accept(&mut nic, &config, syn);
+        assert_eq!(sent_options(&syn_ack).window_scale, Some(shift));
+        // the windows in SYNs are never scaled, in either direction
+        assert_eq!(syn_ack.window_size, u16::MAX);
+        assert_eq!(c.send.wnd, u16::MAX as u32);
+
+        deliver(&mut c, &mut nic, mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        assert_eq!(c.send.wnd, (u16::MAX as u32) << 2);
+        c.unacked.extend(b"hello");
+        c.on_tick(&mut nic).unwrap();
+        let sent = nic.take_segments();
+        assert_eq!(sent[0].0.window_size, (1 << 20 >> shift) as u16);
+    }
+
+    #[test]
+    fn window_scaling_without_peer_option() {
+        let mut nic = mock::Device::default();
+        let config = Config {
+            recv_buffer: 1 << 20,
+            ..config()
+        };
+        let (mut c, syn_ack) = accept(&mut nic, &config, mock::segment(IRS).syn());
+        // a SYN,ACK only carries the option if the SYN did (RFC 7323 S1.3)
+        assert!(sent_options(&syn_ack).window_scale.is_none());
+        assert_eq!(c.recv.wscale, 0);
+
+        deliver(&mut c, &mut nic, mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        assert_eq!(c.send.wnd, u16::MAX as u32);
+        c.unacked.extend(b"hello");
+        c.on_tick(&mut nic).unwrap();
+        let sent = nic.take_segments();
+        assert_eq!(sent[0].0.window_size, u16::MAX);
+    }
+
+    /// A passive connection whose handshake with timestamps is done, the last one from the peer
+    /// being 100.
+    fn established_with_timestamps(nic: &mut mock::Device) -> Connection {
+        let syn = mock::segment(IRS)
+            .syn()
+            .options(&[TcpOptionElement::Timestamp(99, 0)])
+            .unwrap();
+        let (mut c, syn_ack) = accept(nic, &config(), syn);
+        let (_, echo) = sent_options(&syn_ack).timestamp.unwrap();
+        assert_eq!(echo, 99);
+        let ack = mock::segment(IRS + 1)
+            .ack(ISS + 1)
+            .options(&[TcpOptionElement::Timestamp(100, 0)])
+            .unwrap();
+        deliver(&mut c, nic, ack, &[]);
+        assert_eq!(c.state, State::Estab);
+        c
+    }
+
+    #[test]
+    fn timestamps_without_peer_option() {
+        let mut nic = mock::Device::default();
+        let (c, syn_ack) = accept(&mut nic, &config(), mock::segment(IRS).syn());
+        assert!(sent_options(&syn_ack).timestamp.is_none());
+        assert!(c.timestamps.is_none());
+    }
+
+    #[test]
+    fn paws_drops_old_timestamps() {
+        let mut nic = mock::Device::default();
+        let mut c = established_with_timestamps(&mut nic);
+
+        let old = mock::segment(IRS + 1)
+            .ack(ISS + 1)
+            .options(&[TcpOptionElement::Timestamp(50, 0)])
+            .unwrap();
+        deliver(&mut c, &mut nic, old, b"hello");
+        assert!(c.incoming.is_empty());
+        // the ACK tells the peer where we really are
+        let sent = nic.take_segments();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.acknowledgment_number, IRS + 1);
+        assert_eq!(sent_options(&sent[0].0).timestamp.unwrap().1, 100);
+    }
+
+    #[test]
+    fn ts_recent_only_from_acknowledged_sequence_numbers() {
+        let mut nic = mock::Device::default();
+        let mut c = established_with_timestamps(&mut nic);
+
+        let next = mock::segment(IRS + 1)
+            .ack(ISS + 1)
+            .options(&[TcpOptionElement::Timestamp(200, 0)])
+            .unwrap();
+        deliver(&mut c, &mut nic, next, b"hello");
+        assert_eq!(c.incoming.len(), 5);
+        nic.take_segments();
+
+        // past a hole, so it is beyond anything we acknowledged; the ACK that answers it echoes
+        // the timestamp from before the hole
+        let later = mock::segment(IRS + 100)
+            .ack(ISS + 1)
+            .options(&[TcpOptionElement::Timestamp(300, 0)])
+            .unwrap();
+        deliver(&mut c, &mut nic, later, b"world");
+        let sent = nic.take_segments();
+        let (ack, _) = sent.last().unwrap();
+        assert_eq!(ack.acknowledgment_number, IRS + 6);
+        assert_eq!(sent_options(ack).timestamp.unwrap().1, 200);
+    }
+
+    /// The payload sizes of the segments `c` sends for `len` bytes of new data.
+    fn segment_sizes(c: &mut Connection, nic: &mut mock::Device, len: usize) -> Vec<usize> {
+        deliver(c, nic, mock::segment(IRS + 1).ack(ISS + 1), &[]);
+        c.unacked.extend(vec![0; len]);
+        c.on_tick(nic).unwrap();
+        nic.take_segments()
+            .iter()
+            .map(|(_, data)| data.len())
+            .collect()
+    }
+
+    #[test]
+    fn mss_we_advertise() {
+        let mut nic = mock::Device::default();
+        let config = Config {
+            mtu: 1400,
+            ..config()
+        };
+        let (_, syn_ack) = accept(&mut nic, &config, mock::segment(IRS).syn());
+        assert_eq!(sent_options(&syn_ack).mss, Some(1400 - 40));
+    }
+
+    #[test]
+    fn mss_we_honour() {
+        let mut nic = mock::Device::default();
+        let syn = mock::segment(IRS)
+            .syn()
+            .options(&[TcpOptionElement::MaximumSegmentSize(1000)])
+            .unwrap();
+        let (mut c, _) = accept(&mut nic, &config(), syn);
+        assert_eq!(segment_sizes(&mut c, &mut nic, 2000), [1000, 1000]);
+
+        // without the option, RFC 9293 S3.7.1 says to assume 536 bytes
+        let mut c = syn_rcvd(&mut nic);
+        assert_eq!(segment_sizes(&mut c, &mut nic, 1072), [536, 536]);
+
+        // and never more than fits in our own MTU
+        let syn = mock::segment(IRS)
+            .syn()
+            .options(&[TcpOptionElement::MaximumSegmentSize(9000)])
+            .unwrap();
+        let (mut c, _) = accept(&mut nic, &config(), syn);
+        assert_eq!(segment_sizes(&mut c, &mut nic, 2920), [1460, 1460]);
+    }
+}
@@ -0,0 +1,243 @@
+use std::time;
+
+mod cubic;
+mod reno;
+
+pub use cubic::Cubic;
+pub use reno::Reno;
+
+/// A congestion control algorithm, which decides how much data may be in flight.
+///
+/// Windows are in bytes. The connection detects losses and runs fast recovery (RFC 6582) itself;
+/// the algorithm only decides how the window grows and how far it backs off.
+pub trait CongestionControl: Send {
+    /// Start over from the initial window for segments of up to `mss` bytes.
+    fn init(&mut self, mss: u32);
+
+    /// The congestion window.
+    fn window(&self) -> u32;
+
+    /// `acked` bytes were newly acknowledged outside of fast recovery.
+    ///
+    /// `srtt` is the connection's smoothed round-trip time, once it has been measured.
+    fn on_ack(&mut self, acked: u32, srtt: Option<time::Duration>);
+
+    /// Three duplicate ACKs showed a loss with `flight` bytes outstanding.
+    fn on_loss(&mut self, flight: u32);
+
+    /// The retransmission timer expired with `flight` bytes outstanding.
+    ///
+    /// `first` is false if the timer expired again before the previous loss was repaired, in
+    /// which case the path estimate from before the first expiry should be kept (RFC 5681 S3.1).
+    fn on_timeout(&mut self, flight: u32, first: bool);
+}
+
+/// The initial window for segments of up to `mss` bytes (RFC 5681 S3.1).
+fn initial_window(mss: u32) -> u32 {
+    std::cmp::min(4 * mss, std::cmp::max(2 * mss, 4380))
+}
+
+/// How a loss was detected, which decides how we get out of recovery.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Recovery {
+    /// three duplicate ACKs triggered a fast retransmit (RFC 6582)
+    Fast,
+    /// the retransmission timer expired and we are back in slow start
+    Timeout,
+}
+
+/// Loss detection and NewReno fast recovery (RFC 5681, RFC 6582) around a `CongestionControl`.
+pub(crate) struct Congestion {
+    controller: Box<dyn CongestionControl>,
+    mss: u32,
+    /// window on top of the controller's for segments that left the network in fast recovery
+    inflation: u32,
+    /// duplicate ACKs in a row
+    dupacks: u32,
+    /// SND.NXT when we last detected a loss; ACKs below this belong to the same loss event
+    recover: u32,
+    recovery: Option<Recovery>,
+}
+
+impl Congestion {
+    pub(crate) fn new(mut controller: Box<dyn CongestionControl>, mss: u32, iss: u32) -> Self {
+        controller.init(mss);
+        Congestion {
+            controller,
+            mss,
+            inflation: 0,
+            dupacks: 0,
+            recover: iss,
+            recovery: None,
+        }
+    }
+
+    /// Switch to another algorithm, which starts from its initial window.
+    pub(crate) fn set_controller(&mut self, mut controller: Box<dyn CongestionControl>) {
+        controller.init(self.mss);
+        self.controller = controller;
+    }
+
+    /// Start over with segments of up to `mss` bytes, once the handshake has told us how big they
+    /// can be.
+    pub(crate) fn set_mss(&mut self, mss: u32) {
+        self.mss = mss;
+        self.controller.init(mss);
+    }
+
+    /// How many bytes may be in flight.
+    pub(crate) fn window(&self) -> u32 {
+        self.controller.window().saturating_add(self.inflation)
+    }
+
+    /// Whether we are recovering from a loss.
+    pub(crate) fn in_recovery(&self) -> bool {
+        self.recovery.is_some()
+    }
+
+    /// Whether we are resending what was in flight when the retransmission timer expired.
+    pub(crate) fn after_timeout(&self) -> bool {
+        self.recovery == Some(Recovery::Timeout)
+    }
+
+    /// Account for an ACK that acknowledged `acked` new bytes up to `ackn`.
+    ///
+    /// Returns whether the segment at the new SND.UNA should be retransmitted right away, since
+    /// an ACK that doesn't cover everything sent before the loss means the next segment was lost
+    /// as well.
+    pub(crate) fn on_ack(&mut self, ackn: u32, acked: u32, srtt: Option<time::Duration>) -> bool {
+        self.dupacks = 0;
+        let partial = super::wrapping_lt(ackn, self.recover);
+        match self.recovery {
+            Some(Recovery::Fast) if partial => {
+                // deflate by what left the network, but keep room to send the retransmission
+                self.inflation = self.inflation.saturating_sub(acked);
+                if acked >= self.mss {
+                    self.inflation += self.mss;
+                }
+                true
+            }
+            Some(Recovery::Fast) => {
+                self.recovery = None;
+                self.inflation = 0;
+                false
+            }
+            Some(Recovery::Timeout) => {
+                self.controller.on_ack(acked, srtt);
+                if !partial {
+                    self.recovery = None;
+                }
+                partial
+            }
+            None => {
+                self.controller.on_ack(acked, srtt);
+                false
+            }
+        }
+    }
+
+    /// Account for a duplicate ACK (RFC 5681 S2) for `ackn` with `flight` bytes outstanding.
+    ///
+    /// Returns whether to fast retransmit the segment at SND.UNA.
+    pub(crate) fn on_dupack(&mut self, ackn: u32, flight: u32, nxt: u32) -> bool {
+        if self.recovery == Some(Recovery::Fast) {
+            // every duplicate ACK means another segment has left the network
+            self.inflation += self.mss;
+            return false;
+        }
+
+        self.dupacks += 1;
+        // don't fast retransmit twice for losses from the same window (RFC 6582 S3.2)
+        if self.dupacks != 3 || !super::wrapping_lt(self.recover, ackn) {
+            return false;
+        }
+        self.controller.on_loss(flight);
+        self.inflation = 3 * self.mss;
+        self.recover = nxt;
+        self.recovery = Some(Recovery::Fast);
+        true
+    }
+
+    /// Collapse the window after the retransmission timer expired with `flight` bytes outstanding.
+    pub(crate) fn on_timeout(&mut self, flight: u32, nxt: u32) {
+        let first = self.recovery != Some(Recovery::Timeout);
+        self.controller.on_timeout(flight, first);
+        self.inflation = 0;
+        self.dupacks = 0;
+        self.recover = nxt;
+        self.recovery = Some(Recovery::Timeout);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+
+    /// NewReno around Reno, for a connection whose SYN had sequence number 0.
+    fn congestion() -> Congestion {
+        Congestion::new(Box::new(Reno::new()), MSS, 0)
+    }
+
+    #[test]
+    fn fast_recovery_inflates_and_deflates() {
+        let mut cc = congestion();
+        let nxt = 1 + 10 * MSS;
+        assert!(!cc.on_dupack(1, 10 * MSS, nxt));
+        assert!(!cc.on_dupack(1, 10 * MSS, nxt));
+        assert!(cc.on_dupack(1, 10 * MSS, nxt));
+        assert!(cc.in_recovery());
+        // half the flight, plus the three segments that left the network
+        assert_eq!(cc.window(), 5 * MSS + 3 * MSS);
+        assert!(!cc.on_dupack(1, 10 * MSS, nxt));
+        assert_eq!(cc.window(), 5 * MSS + 4 * MSS);
+
+        // a partial ACK takes back what it acknowledged, but leaves room for the retransmission
+        assert!(cc.on_ack(1 + 3 * MSS, 3 * MSS, None));
+        assert_eq!(cc.window(), 5 * MSS + MSS + MSS);
+        assert!(cc.in_recovery());
+
+        // and the full ACK ends recovery with the window at ssthresh
+        assert!(!cc.on_ack(nxt, 7 * MSS, None));
+        assert!(!cc.in_recovery());
+        assert_eq!(cc.window(), 5 * MSS);
+    }
+
+    #[test]
+    fn no_second_fast_retransmit_for_the_same_window() {
+        let mut cc = congestion();
+        let nxt = 1 + 10 * MSS;
+        cc.on_timeout(10 * MSS, nxt);
+        assert!(cc.in_recovery());
+        assert_eq!(cc.window(), MSS);
+
+        // duplicate ACKs for data sent before the timeout don't start fast recovery
+        for _ in 0..3 {
+            assert!(!cc.on_dupack(1 + MSS, 10 * MSS, nxt));
+        }
+        assert_eq!(cc.window(), MSS);
+
+        // a partial ACK keeps going through what is left of the window
+        assert!(cc.on_ack(1 + 2 * MSS, MSS, None));
+        assert!(!cc.on_ack(nxt, 8 * MSS, None));
+        assert!(!cc.in_recovery());
+
+        // but a new loss past the old SND.NXT does
+        let later = nxt + 10 * MSS;
+        assert!(!cc.on_dupack(nxt + MSS, 10 * MSS, later));
+        assert!(!cc.on_dupack(nxt + MSS, 10 * MSS, later));
+        assert!(cc.on_dupack(nxt + MSS, 10 * MSS, later));
+    }
+
+    #[test]
+    fn fast_retransmit_needs_three_in_a_row() {
+        let mut cc = congestion();
+        let nxt = 1 + 10 * MSS;
+        assert!(!cc.on_dupack(1, 10 * MSS, nxt));
+        assert!(!cc.on_dupack(1, 10 * MSS, nxt));
+        assert!(!cc.on_ack(1 + MSS, MSS, None));
+        assert!(!cc.on_dupack(1 + MSS, 9 * MSS, nxt));
+        assert!(!cc.in_recovery());
+    }
+}
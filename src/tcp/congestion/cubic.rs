@@ -0,0 +1,215 @@
+use super::CongestionControl;
+use std::time;
+
+/// Scaling constant for the cubic function, in segments per second cubed.
+const C: f64 = 0.4;
+/// Multiplicative decrease factor on loss.
+const BETA: f64 = 0.7;
+
+/// CUBIC (RFC 9438), which grows the window as a cubic function of the time since the last loss
+/// so that it recovers quickly on paths with a large bandwidth-delay product.
+///
+/// Windows are kept in segments here and only turned into bytes for `window`.
+pub struct Cubic {
+    cwnd: f64,
+    ssthresh: f64,
+    mss: u32,
+    /// window just before the last reduction
+    w_max: f64,
+    /// when the current congestion avoidance epoch started
+    epoch: Option<time::Instant>,
+    /// time it takes the cubic function to get back to `w_max` from the start of the epoch
+    k: f64,
+    /// the window Reno would have in this epoch
+    w_est: f64,
+}
+
+impl Cubic {
+    pub fn new() -> Self {
+        let mut c = Cubic {
+            cwnd: 0.0,
+            ssthresh: f64::INFINITY,
+            mss: 0,
+            w_max: 0.0,
+            epoch: None,
+            k: 0.0,
+            w_est: 0.0,
+        };
+        c.init(crate::tcp::DEFAULT_MSS as u32);
+        c
+    }
+
+    /// The window the cubic function gives `t` seconds into the epoch (RFC 9438 S4.2).
+    fn w_cubic(&self, t: f64) -> f64 {
+        C * (t - self.k).powi(3) + self.w_max
+    }
+
+    fn reduce(&mut self) {
+        self.epoch = None;
+        // fast convergence: if the window didn't get back to where it was, another flow has
+        // probably joined, so give up some more room (RFC 9438 S4.7)
+        self.w_max = if self.cwnd < self.w_max {
+            self.cwnd * (1.0 + BETA) / 2.0
+        } else {
+            self.cwnd
+        };
+        self.ssthresh = f64::max(self.cwnd * BETA, 2.0);
+    }
+}
+
+impl Default for Cubic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionControl for Cubic {
+    fn init(&mut self, mss: u32) {
+        // windows are counted in segments, so there has to be something in one
+        let mss = std::cmp::max(mss, 1);
+        self.cwnd = (super::initial_window(mss) / mss) as f64;
+        self.ssthresh = f64::INFINITY;
+        self.mss = mss;
+        self.w_max = 0.0;
+        self.epoch = None;
+    }
+
+    fn window(&self) -> u32 {
+        (self.cwnd * self.mss as f64) as u32
+    }
+
+    fn on_ack(&mut self, acked: u32, srtt: Option<time::Duration>) {
+        let segments = acked as f64 / self.mss as f64;
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd += f64::min(segments, 1.0);
+            return;
+        }
+
+        let now = time::Instant::now();
+        let epoch = match self.epoch {
+            Some(epoch) => epoch,
+            None => {
+                if self.cwnd < self.w_max {
+                    self.k = ((self.w_max - self.cwnd) / C).cbrt();
+                } else {
+                    self.k = 0.0;
+                    self.w_max = self.cwnd;
+                }
+                self.w_est = self.cwnd;
+                self.epoch = Some(now);
+                now
+            }
+        };
+        let t = now.duration_since(epoch).as_secs_f64();
+        let rtt = srtt.map_or(0.0, |rtt| rtt.as_secs_f64());
+
+        // the Reno-friendly region (RFC 9438 S4.3)
+        let alpha = if self.w_est < self.w_max {
+            3.0 * (1.0 - BETA) / (1.0 + BETA)
+        } else {
+            1.0
+        };
+        self.w_est += alpha * segments / self.cwnd;
+        if self.w_cubic(t) < self.w_est {
+            self.cwnd = self.w_est;
+            return;
+        }
+
+        // aim for where the cubic function will be one round trip from now, growing by at most
+        // half the window per round trip (RFC 9438 S4.4, S4.5)
+        let target = self.w_cubic(t + rtt).clamp(self.cwnd, 1.5 * self.cwnd);
+        self.cwnd += (target - self.cwnd) / self.cwnd * segments;
+    }
+
+    fn on_loss(&mut self, _flight: u32) {
+        self.reduce();
+        self.cwnd = self.ssthresh;
+    }
+
+    fn on_timeout(&mut self, _flight: u32, first: bool) {
+        if first {
+            self.reduce();
+        }
+        self.epoch = None;
+        self.cwnd = 1.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: u32 = 1000;
+
+    /// Cubic in congestion avoidance, just after a loss at a window of 100 segments.
+    fn after_loss() -> Cubic {
+        let mut c = Cubic::new();
+        c.init(MSS);
+        c.cwnd = 100.0;
+        c.ssthresh = 50.0;
+        c.on_loss(100 * MSS);
+        c
+    }
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{a} != {b}");
+    }
+
+    #[test]
+    fn loss_backs_off_by_beta() {
+        let c = after_loss();
+        assert_close(c.w_max, 100.0);
+        assert_close(c.cwnd, 70.0);
+        assert_close(c.ssthresh, 70.0);
+        assert_eq!(c.window(), 70 * MSS);
+    }
+
+    #[test]
+    fn k_reaches_w_max() {
+        let mut c = after_loss();
+        c.on_ack(MSS, None);
+        // (W_max - cwnd) / C = 30 / 0.4
+        assert_close(c.k, 75f64.cbrt());
+        assert_close(c.w_cubic(0.0), 70.0);
+        assert_close(c.w_cubic(c.k), 100.0);
+    }
+
+    #[test]
+    fn reno_friendly_region() {
+        let mut c = after_loss();
+        // a window's worth of ACKs grows the Reno estimate by alpha, which is where the window
+        // goes while the cubic function is still below it
+        c.on_ack(70 * MSS, None);
+        let alpha = 3.0 * (1.0 - BETA) / (1.0 + BETA);
+        assert_close(c.w_est, 70.0 + alpha);
+        assert_close(c.cwnd, c.w_est);
+    }
+
+    #[test]
+    fn fast_convergence() {
+        let mut c = after_loss();
+        // a second loss before the window got back to W_max
+        c.on_loss(70 * MSS);
+        assert_close(c.w_max, 70.0 * (1.0 + BETA) / 2.0);
+        assert_close(c.cwnd, 70.0 * BETA);
+    }
+
+    #[test]
+    fn timeout() {
+        let mut c = after_loss();
+        c.on_timeout(70 * MSS, true);
+        assert_close(c.cwnd, 1.0);
+        assert_close(c.ssthresh, 70.0 * BETA);
+        // the path estimate from the first timeout is kept
+        c.on_timeout(MSS, false);
+        assert_close(c.ssthresh, 70.0 * BETA);
+    }
+
+    #[test]
+    fn zero_mss() {
+        let mut c = Cubic::new();
+        c.init(0);
+        assert!(c.window() > 0);
+    }
+}
@@ -0,0 +1,95 @@
+use super::CongestionControl;
+use std::time;
+
+/// Reno's window growth (RFC 5681): slow start, then about one segment per round trip, halving
+/// the window on loss.
+pub struct Reno {
+    cwnd: u32,
+    ssthresh: u32,
+    mss: u32,
+    /// bytes acknowledged towards the next increase in congestion avoidance
+    acked: u32,
+}
+
+impl Reno {
+    pub fn new() -> Self {
+        let mss = crate::tcp::DEFAULT_MSS as u32;
+        Reno {
+            cwnd: super::initial_window(mss),
+            ssthresh: u32::MAX,
+            mss,
+            acked: 0,
+        }
+    }
+
+    fn loss_threshold(&self, flight: u32) -> u32 {
+        std::cmp::max(flight / 2, 2 * self.mss)
+    }
+}
+
+impl Default for Reno {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CongestionControl for Reno {
+    fn init(&mut self, mss: u32) {
+        *self = Reno {
+            cwnd: super::initial_window(mss),
+            mss,
+            ..Reno::new()
+        };
+    }
+
+    fn window(&self) -> u32 {
+        self.cwnd
+    }
+
+    fn on_ack(&mut self, acked: u32, _srtt: Option<time::Duration>) {
+        if self.cwnd < self.ssthresh {
+            // slow start
+            self.cwnd = self.cwnd.saturating_add(std::cmp::min(acked, self.mss));
+        } else {
+            // congestion avoidance, about one segment per round trip
+            self.acked = self.acked.saturating_add(acked);
+            if self.acked >= self.cwnd {
+                self.acked -= self.cwnd;
+                self.cwnd = self.cwnd.saturating_add(self.mss);
+            }
+        }
+    }
+
+    fn on_loss(&mut self, flight: u32) {
+        self.ssthresh = self.loss_threshold(flight);
+        self.cwnd = self.ssthresh;
+        self.acked = 0;
+    }
+
+    fn on_timeout(&mut self, flight: u32, first: bool) {
+        if first {
+            self.ssthresh = self.loss_threshold(flight);
+        }
+        self.cwnd = self.mss;
+        self.acked = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_saturates() {
+        let mut reno = Reno::new();
+        reno.cwnd = u32::MAX - 100;
+        reno.on_ack(reno.mss, None);
+        assert_eq!(reno.window(), u32::MAX);
+
+        reno.ssthresh = 0;
+        for _ in 0..3 {
+            reno.on_ack(u32::MAX, None);
+        }
+        assert_eq!(reno.window(), u32::MAX);
+    }
+}
@@ -0,0 +1,118 @@
+use etherparse::TcpOptionElement;
+
+/// The TCP options we understand, as found in a segment.
+#[derive(Default)]
+pub(crate) struct Options {
+    /// the sender can take SACK blocks (RFC 2018 S2), only valid on a SYN
+    pub(crate) sack_permitted: bool,
+    /// `[start, end)` ranges the sender has received beyond its cumulative ACK
+    pub(crate) sack: Vec<(u32, u32)>,
+    /// how far the sender shifts the windows it advertises (RFC 7323 S2), only valid on a SYN
+    pub(crate) window_scale: Option<u8>,
+    /// the largest segment the sender takes, only valid on a SYN
+    pub(crate) mss: Option<u16>,
+    /// the sender's timestamp and the one it echoes (RFC 7323 S3)
+    pub(crate) timestamp: Option<(u32, u32)>,
+}
+
+impl Options {
+    pub(crate) fn parse(tcp_header: &etherparse::TcpHeaderSlice) -> Self {
+        let mut options = Options::default();
+        for option in tcp_header.options_iterator() {
+            match option {
+                Ok(TcpOptionElement::SelectiveAcknowledgementPermitted) => {
+                    options.sack_permitted = true;
+                }
+                Ok(TcpOptionElement::MaximumSegmentSize(mss)) => {
+                    options.mss = Some(mss);
+                }
+                Ok(TcpOptionElement::WindowScale(shift)) => {
+                    options.window_scale = Some(shift);
+                }
+                Ok(TcpOptionElement::Timestamp(val, echo)) => {
+                    options.timestamp = Some((val, echo));
+                }
+                Ok(TcpOptionElement::SelectiveAcknowledgement(first, rest)) => {
+                    options.sack.push(first);
+                    options.sack.extend(rest.iter().flatten());
+                }
+                Ok(_) => {}
+                // without the length of a bad option we can't find the ones after it
+                Err(_) => break,
+            }
+        }
+        options
+    }
+}
+
+/// A SACK option carrying as many of `blocks` as fit in `room` bytes of option space.
+pub(crate) fn sack(blocks: &[(u32, u32)], room: usize) -> Option<TcpOptionElement> {
+    // kind and length, then eight bytes per block
+    let n = std::cmp::min(blocks.len(), room.saturating_sub(2) / 8);
+    let (&first, rest) = blocks[..n].split_first()?;
+    let mut more = [None; 3];
+    for (slot, &block) in more.iter_mut().zip(rest) {
+        *slot = Some(block);
+    }
+    Some(TcpOptionElement::SelectiveAcknowledgement(first, more))
+}
+
+/// How many bytes `options` take up in the header, including padding to four bytes.
+pub(crate) fn len(options: &[TcpOptionElement]) -> usize {
+    let len: usize = options
+        .iter()
+        .map(|option| match option {
+            TcpOptionElement::Noop => 1,
+            TcpOptionElement::MaximumSegmentSize(_) => 4,
+            TcpOptionElement::WindowScale(_) => 3,
+            TcpOptionElement::SelectiveAcknowledgementPermitted => 2,
+            TcpOptionElement::SelectiveAcknowledgement(_, rest) => {
+                10 + 8 * rest.iter().flatten().count()
+            }
+            TcpOptionElement::Timestamp(_, _) => 10,
+        })
+        .sum();
+    len.div_ceil(4) * 4
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCKS: [(u32, u32); 5] = [(10, 20), (30, 40), (50, 60), (70, 80), (90, 100)];
+
+    fn count(option: Option<TcpOptionElement>) -> usize {
+        match option {
+            Some(TcpOptionElement::SelectiveAcknowledgement(first, rest)) => {
+                assert_eq!(first, BLOCKS[0]);
+                for (block, expected) in rest.iter().flatten().zip(&BLOCKS[1..]) {
+                    assert_eq!(block, expected);
+                }
+                1 + rest.iter().flatten().count()
+            }
+            None => 0,
+            Some(other) => panic!("not a SACK option: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn sack_block_limits() {
+        // all 40 bytes of option space but the two NOPs in front
+        assert_eq!(count(sack(&BLOCKS, 38)), 4);
+        // alongside timestamps
+        assert_eq!(count(sack(&BLOCKS, 26)), 3);
+        assert_eq!(count(sack(&BLOCKS[..2], 38)), 2);
+        // not even room for one block
+        assert_eq!(count(sack(&BLOCKS, 9)), 0);
+        assert_eq!(count(sack(&[], 38)), 0);
+    }
+
+    #[test]
+    fn sack_len() {
+        for n in 1..=4 {
+            let option = sack(&BLOCKS[..n], 38).unwrap();
+            // the same padding as the two NOPs we put in front
+            assert_eq!(len(&[option]), 2 + 8 * n + 2);
+        }
+    }
+}
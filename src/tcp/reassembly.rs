@@ -0,0 +1,195 @@
+/// Data that arrived ahead of RCV.NXT, held until the gap in front of it is filled.
+///
+/// Segments are kept as non-overlapping runs of bytes sorted by sequence number, all of which
+/// start after RCV.NXT. Offsets are taken relative to RCV.NXT so that ordering survives sequence
+/// number wrap-around.
+#[derive(Default)]
+pub(crate) struct Reassembly {
+    segments: Vec<(u32, Vec<u8>)>,
+    /// sequence number of the peer's FIN, once we have seen it
+    pub(crate) fin: Option<u32>,
+    /// where the most recently stored data starts
+    recent: Option<u32>,
+}
+
+impl Reassembly {
+    /// Store `data` starting at sequence number `seq`, merging it with any runs it overlaps.
+    ///
+    /// Bytes before `nxt` have already been delivered and are dropped.
+    pub(crate) fn insert(&mut self, nxt: u32, seq: u32, data: &[u8]) {
+        let behind = nxt.wrapping_sub(seq) as i32;
+        let (start, data) = if behind > 0 {
+            if behind as usize >= data.len() {
+                return;
+            }
+            (0, &data[behind as usize..])
+        } else {
+            (seq.wrapping_sub(nxt), data)
+        };
+        if data.is_empty() {
+            return;
+        }
+        self.recent = Some(nxt.wrapping_add(start));
+        let end = start + data.len() as u32;
+        let offset = |seq: u32| seq.wrapping_sub(nxt);
+
+        // fold every run that overlaps or touches [start, end) into a single one
+        let first = self
+            .segments
+            .iter()
+            .position(|(s, d)| offset(*s) + d.len() as u32 >= start)
+            .unwrap_or(self.segments.len());
+        let last = self.segments[first..]
+            .iter()
+            .position(|(s, _)| offset(*s) > end)
+            .map_or(self.segments.len(), |i| first + i);
+        let merged: Vec<_> = self.segments.drain(first..last).collect();
+
+        let merged_start = merged.first().map_or(start, |(s, _)| offset(*s).min(start));
+        let merged_end = merged
+            .last()
+            .map_or(end, |(s, d)| (offset(*s) + d.len() as u32).max(end));
+
+        let mut buf = vec![0; (merged_end - merged_start) as usize];
+        for (s, d) in &merged {
+            let at = (offset(*s) - merged_start) as usize;
+            buf[at..at + d.len()].copy_from_slice(d);
+        }
+        let at = (start - merged_start) as usize;
+        buf[at..at + data.len()].copy_from_slice(data);
+
+        self.segments
+            .insert(first, (nxt.wrapping_add(merged_start), buf));
+    }
+
+    /// Whether we hold no data beyond RCV.NXT.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// The runs we hold as `[start, end)` sequence number ranges, to report in SACK blocks.
+    ///
+    /// The run with the most recently stored data comes first, as RFC 2018 S4 asks for.
+    pub(crate) fn blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks: Vec<_> = self
+            .segments
+            .iter()
+            .map(|(s, d)| (*s, s.wrapping_add(d.len() as u32)))
+            .collect();
+        if let Some(recent) = self.recent {
+            let holds_recent = |&(s, e): &(u32, u32)| recent.wrapping_sub(s) < e.wrapping_sub(s);
+            if let Some(i) = blocks.iter().position(holds_recent) {
+                blocks[..=i].rotate_right(1);
+            }
+        }
+        blocks
+    }
+
+    /// Take the run that continues the stream at `nxt`, if we have it.
+    pub(crate) fn pop(&mut self, nxt: u32) -> Option<Vec<u8>> {
+        while let Some((start, _)) = self.segments.first() {
+            let ahead = start.wrapping_sub(nxt) as i32;
+            if ahead > 0 {
+                return None;
+            }
+
+            // anything already delivered in order is dropped from the front of the run
+            let (_, mut data) = self.segments.remove(0);
+            let skip = ahead.unsigned_abs() as usize;
+            if skip < data.len() {
+                data.drain(..skip);
+                return Some(data);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlapping_runs_merge() {
+        let mut r = Reassembly::default();
+        r.insert(0, 10, b"abcde");
+        r.insert(0, 13, b"defgh");
+        assert_eq!(r.blocks(), vec![(10, 18)]);
+        // one segment covering two runs and the gap between them
+        r.insert(0, 30, b"xyz");
+        r.insert(0, 16, b"ghijklmnopqrstuvwxyz");
+        assert_eq!(r.blocks(), vec![(10, 36)]);
+
+        r.insert(0, 0, b"0123456789");
+        assert_eq!(r.pop(0).unwrap(), b"0123456789abcdefghijklmnopqrstuvwxyz");
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn touching_runs_merge() {
+        let mut r = Reassembly::default();
+        r.insert(0, 10, b"abc");
+        r.insert(0, 16, b"ghi");
+        r.insert(0, 13, b"def");
+        assert_eq!(r.blocks(), vec![(10, 19)]);
+        assert_eq!(r.pop(10).unwrap(), b"abcdefghi");
+    }
+
+    #[test]
+    fn separate_runs() {
+        let mut r = Reassembly::default();
+        r.insert(0, 20, b"uvw");
+        r.insert(0, 10, b"abc");
+        r.insert(0, 30, b"xyz");
+        // the most recent first, the rest in order
+        assert_eq!(r.blocks(), vec![(30, 33), (10, 13), (20, 23)]);
+        r.insert(0, 11, b"b");
+        assert_eq!(r.blocks(), vec![(10, 13), (20, 23), (30, 33)]);
+
+        assert_eq!(r.pop(0), None);
+        assert_eq!(r.pop(10).unwrap(), b"abc");
+        assert_eq!(r.pop(13), None);
+        assert_eq!(r.blocks(), vec![(20, 23), (30, 33)]);
+    }
+
+    #[test]
+    fn straddling_nxt() {
+        let mut r = Reassembly::default();
+        // what is before RCV.NXT was delivered already
+        r.insert(100, 95, b"0123456789");
+        assert_eq!(r.blocks(), vec![(100, 105)]);
+        r.insert(100, 90, b"0123456789");
+        assert!(!r.is_empty());
+        assert_eq!(r.pop(100).unwrap(), b"56789");
+
+        // and data that is all behind is dropped
+        r.insert(100, 90, b"0123456789");
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn pop_skips_delivered() {
+        let mut r = Reassembly::default();
+        r.insert(0, 10, b"abcdef");
+        r.insert(0, 20, b"uvw");
+        // a retransmission filled the gap and then some
+        assert_eq!(r.pop(13).unwrap(), b"def");
+        r.insert(16, 18, b"st");
+        // a run that was delivered entirely is dropped
+        assert_eq!(r.pop(25), None);
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn sequence_wrap() {
+        let nxt = u32::MAX - 3;
+        let mut r = Reassembly::default();
+        r.insert(nxt, 3, b"cd");
+        r.insert(nxt, u32::MAX - 1, b"xyzab");
+        assert_eq!(r.blocks(), vec![(u32::MAX - 1, 5)]);
+        assert_eq!(r.pop(nxt), None);
+        r.insert(nxt, nxt, b"vw");
+        assert_eq!(r.pop(nxt).unwrap(), b"vwxyzabcd");
+        assert!(r.is_empty());
+    }
+}
@@ -0,0 +1,151 @@
+/// What the peer has told us through SACK blocks it holds beyond SND.UNA (RFC 6675 S3).
+///
+/// Blocks are kept as non-overlapping `[start, end)` ranges sorted by sequence number, all of
+/// which lie between SND.UNA and SND.NXT.
+#[derive(Default)]
+pub(crate) struct Scoreboard {
+    blocks: Vec<(u32, u32)>,
+}
+
+impl Scoreboard {
+    /// Record a SACK block, ignoring any that don't describe data we sent and haven't had
+    /// cumulatively acknowledged.
+    pub(crate) fn insert(&mut self, una: u32, nxt: u32, start: u32, end: u32) {
+        let offset = |seq: u32| seq.wrapping_sub(una);
+        let (start, end) = (offset(start), offset(end));
+        if start >= end || end > offset(nxt) {
+            return;
+        }
+
+        let mut blocks: Vec<_> = self
+            .blocks
+            .iter()
+            .map(|&(s, e)| (offset(s), offset(e)))
+            .collect();
+        blocks.push((start, end));
+        blocks.sort_unstable();
+
+        self.blocks.clear();
+        for (s, e) in blocks {
+            match self.blocks.last_mut() {
+                Some((_, last)) if s <= *last => *last = std::cmp::max(*last, e),
+                _ => self.blocks.push((s, e)),
+            }
+        }
+        for (s, e) in &mut self.blocks {
+            *s = una.wrapping_add(*s);
+            *e = una.wrapping_add(*e);
+        }
+    }
+
+    /// Drop everything the cumulative ACK up to `una` now covers.
+    pub(crate) fn advance(&mut self, una: u32) {
+        self.blocks.retain(|&(_, e)| super::wrapping_lt(una, e));
+        if let Some((s, _)) = self.blocks.first_mut() {
+            if super::wrapping_lt(*s, una) {
+                *s = una;
+            }
+        }
+    }
+
+    /// Forget what the peer told us, since it is allowed to drop data it SACKed (RFC 2018 S8).
+    pub(crate) fn clear(&mut self) {
+        self.blocks.clear();
+    }
+
+    /// The first range at or after `from` that the peer has not SACKed, but that lies before
+    /// data it has.
+    ///
+    /// Anything past the last SACK block may simply still be in flight, so it is never reported.
+    pub(crate) fn next_hole(&self, una: u32, from: u32) -> Option<(u32, u32)> {
+        let mut at = if super::wrapping_lt(from, una) {
+            una
+        } else {
+            from
+        };
+        for &(s, e) in &self.blocks {
+            if super::wrapping_lt(at, s) {
+                return Some((at, s));
+            }
+            if super::wrapping_lt(at, e) {
+                at = e;
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_merge() {
+        let mut sb = Scoreboard::default();
+        sb.insert(0, 100, 40, 50);
+        sb.insert(0, 100, 10, 20);
+        sb.insert(0, 100, 60, 70);
+        assert_eq!(sb.blocks, vec![(10, 20), (40, 50), (60, 70)]);
+        // overlapping, then touching
+        sb.insert(0, 100, 45, 55);
+        sb.insert(0, 100, 55, 60);
+        assert_eq!(sb.blocks, vec![(10, 20), (40, 70)]);
+        // one block that covers others
+        sb.insert(0, 100, 5, 80);
+        assert_eq!(sb.blocks, vec![(5, 80)]);
+    }
+
+    #[test]
+    fn bogus_blocks_ignored() {
+        let mut sb = Scoreboard::default();
+        // empty, beyond SND.NXT, and already cumulatively acknowledged
+        sb.insert(10, 100, 30, 30);
+        sb.insert(10, 100, 90, 110);
+        sb.insert(10, 100, 0, 5);
+        assert!(sb.blocks.is_empty());
+    }
+
+    #[test]
+    fn advance() {
+        let mut sb = Scoreboard::default();
+        sb.insert(0, 100, 10, 20);
+        sb.insert(0, 100, 40, 50);
+        // into the middle of the first block
+        sb.advance(15);
+        assert_eq!(sb.blocks, vec![(15, 20), (40, 50)]);
+        // past it, but not to the next one
+        sb.advance(30);
+        assert_eq!(sb.blocks, vec![(40, 50)]);
+        sb.advance(50);
+        assert!(sb.blocks.is_empty());
+    }
+
+    #[test]
+    fn next_hole() {
+        let mut sb = Scoreboard::default();
+        assert_eq!(sb.next_hole(0, 0), None);
+        sb.insert(0, 100, 10, 20);
+        sb.insert(0, 100, 40, 50);
+        assert_eq!(sb.next_hole(0, 0), Some((0, 10)));
+        assert_eq!(sb.next_hole(0, 5), Some((5, 10)));
+        assert_eq!(sb.next_hole(0, 10), Some((20, 40)));
+        assert_eq!(sb.next_hole(0, 25), Some((25, 40)));
+        // what lies beyond the last block may still be in flight
+        assert_eq!(sb.next_hole(0, 45), None);
+        // nothing before SND.UNA is a hole
+        sb.advance(15);
+        assert_eq!(sb.next_hole(15, 5), Some((20, 40)));
+    }
+
+    #[test]
+    fn sequence_wrap() {
+        let una = u32::MAX - 9;
+        let mut sb = Scoreboard::default();
+        sb.insert(una, 20, 5, 10);
+        sb.insert(una, 20, u32::MAX - 4, 5);
+        assert_eq!(sb.blocks, vec![(u32::MAX - 4, 10)]);
+        assert_eq!(sb.next_hole(una, una), Some((una, u32::MAX - 4)));
+        sb.advance(2);
+        assert_eq!(sb.blocks, vec![(2, 10)]);
+    }
+}
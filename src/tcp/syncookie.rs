@@ -0,0 +1,231 @@
+use super::options::Options;
+use std::hash::{BuildHasher, RandomState};
+use std::net::Ipv4Addr;
+use std::time;
+
+/// How long one value of the cookie's counter lasts.
+const PERIOD: time::Duration = time::Duration::from_secs(64);
+/// How many periods after the one it was made in a cookie is still accepted.
+const MAX_AGE: u32 = 2;
+/// The MSS values a cookie can hold; the peer's MSS is rounded down to one of these.
+const MSS_TABLE: [u16; 4] = [536, 1220, 1440, 1460];
+/// Window scale field value for a peer that doesn't scale its window.
+const NO_WINDOW_SCALE: u32 = 0xf;
+/// The low bits of a cookie, which hold the hash.
+const HASH_BITS: u32 = 22;
+
+/// Stateless SYN cookies (RFC 4987 S3.6), which let a listener with a full SYN backlog answer a
+/// SYN without keeping anything for it.
+///
+/// The cookie is the ISS of our SYN,ACK. From the top, it holds the last three bits of a counter
+/// that advances every 64 seconds, the peer's MSS rounded down to one of four values, whether the
+/// peer can take SACK blocks, and its window scale. The low 22 bits are a keyed hash over all of
+/// that and the connection, which the peer can't compute but has to echo back in the final ACK of
+/// the handshake.
+pub(crate) struct SynCookies {
+    secret: RandomState,
+    clock: time::Instant,
+}
+
+impl Default for SynCookies {
+    fn default() -> Self {
+        SynCookies {
+            secret: RandomState::new(),
+            clock: time::Instant::now(),
+        }
+    }
+}
+
+impl SynCookies {
+    fn counter(&self) -> u32 {
+        (self.clock.elapsed().as_secs() / PERIOD.as_secs()) as u32
+    }
+
+    fn hash(
+        &self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        irs: u32,
+        counter: u32,
+        fields: u32,
+    ) -> u32 {
+        let hash = self.secret.hash_one((local, remote, irs, counter, fields));
+        hash as u32 & ((1 << HASH_BITS) - 1)
+    }
+
+    /// The ISS to answer a SYN from `remote` with, given its sequence number `irs` and `options`.
+    ///
+    /// Returns `None` if the peer's MSS is below the smallest one a cookie can hold, since
+    /// rounding it up could have us send segments the peer can't take.
+    pub(crate) fn make(
+        &self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        irs: u32,
+        options: &Options,
+    ) -> Option<u32> {
+        self.make_at(self.counter(), local, remote, irs, options)
+    }
+
+    fn make_at(
+        &self,
+        counter: u32,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        irs: u32,
+        options: &Options,
+    ) -> Option<u32> {
+        let mss = options.mss.unwrap_or(super::DEFAULT_MSS as u16);
+        let mss_index = MSS_TABLE.iter().rposition(|&m| m <= mss)? as u32;
+        let wscale = options.window_scale.map_or(NO_WINDOW_SCALE, |shift| {
+            std::cmp::min(shift, super::MAX_WINDOW_SHIFT) as u32
+        });
+        let fields =
+            (counter & 0x7) << 7 | mss_index << 5 | (options.sack_permitted as u32) << 4 | wscale;
+        Some(fields << HASH_BITS | self.hash(local, remote, irs, counter, fields))
+    }
+
+    /// Check the `cookie` a peer echoed back for its SYN with sequence number `irs`.
+    ///
+    /// Returns what the SYN's options told us if the cookie is genuine and recent.
+    pub(crate) fn check(
+        &self,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        irs: u32,
+        cookie: u32,
+    ) -> Option<Options> {
+        self.check_at(self.counter(), local, remote, irs, cookie)
+    }
+
+    fn check_at(
+        &self,
+        now: u32,
+        local: (Ipv4Addr, u16),
+        remote: (Ipv4Addr, u16),
+        irs: u32,
+        cookie: u32,
+    ) -> Option<Options> {
+        let fields = cookie >> HASH_BITS;
+        let age = now.wrapping_sub(fields >> 7) & 0x7;
+        if age > MAX_AGE || age > now {
+            return None;
+        }
+        let hash = self.hash(local, remote, irs, now - age, fields);
+        if cookie & ((1 << HASH_BITS) - 1) != hash {
+            return None;
+        }
+
+        let wscale = fields & 0xf;
+        Some(Options {
+            mss: Some(MSS_TABLE[(fields >> 5 & 0x3) as usize]),
+            sack_permitted: fields >> 4 & 0x1 == 1,
+            window_scale: (wscale != NO_WINDOW_SCALE).then_some(wscale as u8),
+            ..Options::default()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOCAL: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 2), 80);
+    const REMOTE: (Ipv4Addr, u16) = (Ipv4Addr::new(10, 0, 0, 1), 40000);
+    const IRS: u32 = 1000;
+
+    fn options(mss: u16) -> Options {
+        Options {
+            mss: Some(mss),
+            sack_permitted: true,
+            window_scale: Some(7),
+            ..Options::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let cookies = SynCookies::default();
+        let cookie = cookies.make(LOCAL, REMOTE, IRS, &options(1460)).unwrap();
+        let options = cookies.check(LOCAL, REMOTE, IRS, cookie).unwrap();
+        assert_eq!(options.mss, Some(1460));
+        assert!(options.sack_permitted);
+        assert_eq!(options.window_scale, Some(7));
+    }
+
+    #[test]
+    fn without_options() {
+        let cookies = SynCookies::default();
+        let cookie = cookies
+            .make(LOCAL, REMOTE, IRS, &Options::default())
+            .unwrap();
+        let options = cookies.check(LOCAL, REMOTE, IRS, cookie).unwrap();
+        assert_eq!(options.mss, Some(536));
+        assert!(!options.sack_permitted);
+        assert_eq!(options.window_scale, None);
+    }
+
+    #[test]
+    fn mss_rounds_down() {
+        let cookies = SynCookies::default();
+        for (mss, expected) in [
+            (536, 536),
+            (1000, 536),
+            (1220, 1220),
+            (1459, 1440),
+            (9000, 1460),
+        ] {
+            let cookie = cookies.make(LOCAL, REMOTE, IRS, &options(mss)).unwrap();
+            let options = cookies.check(LOCAL, REMOTE, IRS, cookie).unwrap();
+            assert_eq!(options.mss, Some(expected));
+        }
+    }
+
+    #[test]
+    fn mss_too_small() {
+        let cookies = SynCookies::default();
+        assert_eq!(cookies.make(LOCAL, REMOTE, IRS, &options(535)), None);
+    }
+
+    #[test]
+    fn ages_out() {
+        let cookies = SynCookies::default();
+        let made = 10;
+        let cookie = cookies
+            .make_at(made, LOCAL, REMOTE, IRS, &options(1460))
+            .unwrap();
+        for now in made..=made + MAX_AGE {
+            assert!(cookies.check_at(now, LOCAL, REMOTE, IRS, cookie).is_some());
+        }
+        assert!(cookies
+            .check_at(made + MAX_AGE + 1, LOCAL, REMOTE, IRS, cookie)
+            .is_none());
+        // the counter only has three bits in the cookie, but wrapping around doesn't revive it
+        assert!(cookies
+            .check_at(made + 8, LOCAL, REMOTE, IRS, cookie)
+            .is_none());
+        // nor can a cookie come from the future
+        assert!(cookies
+            .check_at(made - 1, LOCAL, REMOTE, IRS, cookie)
+            .is_none());
+    }
+
+    #[test]
+    fn tampered() {
+        let cookies = SynCookies::default();
+        let cookie = cookies.make(LOCAL, REMOTE, IRS, &options(1460)).unwrap();
+        // a flipped hash bit, a different MSS, or another connection
+        assert!(cookies.check(LOCAL, REMOTE, IRS, cookie ^ 1).is_none());
+        assert!(cookies
+            .check(LOCAL, REMOTE, IRS, cookie ^ 1 << (HASH_BITS + 5))
+            .is_none());
+        assert!(cookies.check(LOCAL, REMOTE, IRS + 1, cookie).is_none());
+        assert!(cookies
+            .check(LOCAL, (REMOTE.0, REMOTE.1 + 1), IRS, cookie)
+            .is_none());
+        // nor does a cookie carry over to another set of secrets
+        assert!(SynCookies::default()
+            .check(LOCAL, REMOTE, IRS, cookie)
+            .is_none());
+    }
+}